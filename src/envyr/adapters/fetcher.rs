@@ -1,23 +1,135 @@
+use super::super::error::EnvyrError;
 use super::git::GitFetcher;
+use super::hg::{is_hg_url, HgFetcher};
+use super::local::local_path;
+use super::zip::{is_zip_url, ZipFetcher};
 use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
 // Fetcher abstracts over the different ways to source a project.
 pub trait Fetcher {
-    fn fetch(&self, url: &str, tag: &str, refresh: bool) -> Result<PathBuf>;
+    fn fetch(&self, url: &str, tag: &str, refresh: bool) -> Result<PathBuf, EnvyrError>;
+}
+
+// Options that tune how remote sources are fetched.
+#[derive(Default, Clone, Args, Serialize, Deserialize)]
+pub struct FetchOpts {
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "History depth for git clones. Widened automatically if a tag/commit is missing. 0 clones the full history."
+    )]
+    #[serde(default)]
+    pub depth: u32,
+
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "Attempts for network-touching git commands. Only connection errors are retried, with exponential backoff. 1 disables retrying."
+    )]
+    #[serde(default = "default_fetch_retries")]
+    pub fetch_retries: u32,
+
+    #[arg(
+        long,
+        help = "SSH private key used for git fetches, overriding the ssh-agent and GIT_SSH_COMMAND."
+    )]
+    #[serde(default)]
+    pub git_ssh_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Initialize and update git submodules recursively when cloning, refreshing and switching tags."
+    )]
+    #[serde(default)]
+    pub submodules: bool,
+
+    #[arg(
+        long,
+        value_name = "BRANCH",
+        help = "Branch that `latest` tracks for git sources, instead of the remote HEAD or the fallback branches."
+    )]
+    #[serde(default)]
+    pub default_branch: Option<String>,
+
+    // From fallback_branches in ~/.envyr/config.toml, set for each fetch.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub fallback_branches: Vec<String>,
+
+    // Never stored with aliases.
+    #[arg(
+        long,
+        env = "ENVYR_GIT_TOKEN",
+        hide_env_values = true,
+        help = "Token sent as an Authorization header on HTTPS git fetches, ahead of any credential helper."
+    )]
+    #[serde(skip)]
+    pub git_token: Option<String>,
+
+    // Never stored with aliases, it may carry credentials. Without it, git and
+    // curl pick up HTTPS_PROXY/NO_PROXY from the inherited environment.
+    #[arg(
+        long,
+        help = "Proxy URL for git and archive fetches, overriding HTTPS_PROXY/NO_PROXY from the environment."
+    )]
+    #[serde(skip)]
+    pub proxy: Option<String>,
+
+    // From --total-timeout, set for each run.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub deadline: Option<std::time::Instant>,
+}
+
+fn default_fetch_retries() -> u32 {
+    3
+}
+
+impl std::fmt::Debug for FetchOpts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FetchOpts")
+            .field("depth", &self.depth)
+            .field("fetch_retries", &self.fetch_retries)
+            .field("git_ssh_key", &self.git_ssh_key)
+            .field("submodules", &self.submodules)
+            .field("default_branch", &self.default_branch)
+            .field("fallback_branches", &self.fallback_branches)
+            .field("git_token", &self.git_token.as_ref().map(|_| "***"))
+            .field(
+                "proxy",
+                &self
+                    .proxy
+                    .as_ref()
+                    .map(|p| if p.contains('@') { "***" } else { p.as_str() }),
+            )
+            .field("deadline", &self.deadline)
+            .finish()
+    }
 }
 
 struct NoopFetcher {}
 
 impl Fetcher for NoopFetcher {
-    fn fetch(&self, url: &str, _tag: &str, _refresh: bool) -> Result<PathBuf> {
-        Ok(PathBuf::from(url))
+    fn fetch(&self, url: &str, _tag: &str, _refresh: bool) -> Result<PathBuf, EnvyrError> {
+        Ok(local_path(url)?)
     }
 }
 
-pub fn get_fetcher(url: &str, storage_dir: PathBuf) -> Result<Box<dyn Fetcher>> {
-    if url.starts_with("git") {
-        return Ok(Box::new(GitFetcher::new(storage_dir)?));
+pub fn get_fetcher(url: &str, storage_dir: PathBuf, opts: FetchOpts) -> Result<Box<dyn Fetcher>> {
+    if is_hg_url(url) {
+        return Ok(Box::new(HgFetcher::new(storage_dir)?));
+    }
+    if is_zip_url(url) {
+        return Ok(Box::new(ZipFetcher::new(storage_dir, opts)?));
+    }
+    let https_git =
+        (url.starts_with("https://") || url.starts_with("http://")) && url.ends_with(".git");
+    if url.starts_with("git") || https_git {
+        return Ok(Box::new(GitFetcher::new(storage_dir, opts)?));
     }
     Ok(Box::new(NoopFetcher {}))
 }
@@ -0,0 +1,108 @@
+// This adapter allows using mercurial respositories as a source for scripts.
+
+use super::super::error::EnvyrError;
+use super::fetcher::Fetcher;
+use super::git::get_storage_path;
+use anyhow::Result;
+use log::debug;
+use std::path::{Path, PathBuf};
+
+pub struct HgFetcher {
+    storage_dir_root: PathBuf,
+}
+
+impl HgFetcher {
+    pub fn new(storage_dir_root: PathBuf) -> Result<Self> {
+        if !storage_dir_root.exists() {
+            std::fs::create_dir_all(&storage_dir_root)?;
+        }
+        Ok(Self { storage_dir_root })
+    }
+}
+
+impl Fetcher for HgFetcher {
+    fn fetch(&self, url: &str, version: &str, refresh: bool) -> Result<PathBuf, EnvyrError> {
+        let remote = get_remote(url);
+        let path = self.storage_dir_root.join(get_hg_storage_path(remote)?);
+        if path.exists() {
+            debug!("Clone already exists: {:?}", path);
+            if refresh {
+                hg(&path, &["pull", "-u"], "pull mercurial repository")?;
+            }
+        } else {
+            debug!("Cloning mercurial repository: {}", remote);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            let out = std::process::Command::new("hg")
+                .arg("clone")
+                .arg(remote)
+                .arg(&path)
+                .output()?;
+            if !out.status.success() {
+                return Err(EnvyrError::FetchFailed(format!(
+                    "Failed to clone mercurial repository: {:?}",
+                    String::from_utf8(out.stderr),
+                )));
+            }
+        }
+        // Latest follows the tip of the default branch.
+        let revision = if version == "latest" {
+            "default"
+        } else {
+            version
+        };
+        debug!("Updating to revision: {}", revision);
+        hg(&path, &["update", "-r", revision], "update to revision")?;
+        Ok(path)
+    }
+}
+
+// Mercurial sources are given as `hg+<url>` or as remotes ending in `.hg`.
+pub fn is_hg_url(url: &str) -> bool {
+    url.starts_with("hg+") || url.ends_with(".hg")
+}
+
+fn get_remote(url: &str) -> &str {
+    url.strip_prefix("hg+").unwrap_or(url)
+}
+
+fn get_hg_storage_path(remote: &str) -> Result<PathBuf, EnvyrError> {
+    get_storage_path(remote.strip_suffix(".hg").unwrap_or(remote))
+}
+
+fn hg(path: &Path, args: &[&str], action: &str) -> Result<(), EnvyrError> {
+    let out = std::process::Command::new("hg")
+        .args(args)
+        .current_dir(path)
+        .output()?;
+    if !out.status.success() {
+        return Err(EnvyrError::FetchFailed(format!(
+            "Failed to {}: {:?}",
+            action,
+            String::from_utf8(out.stderr),
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hg_unwrapping() {
+        assert!(is_hg_url("hg+https://hg.example.com/tools/deploy"));
+        assert!(is_hg_url("ssh://hg@hg.example.com/tools/deploy.hg"));
+        assert!(!is_hg_url("git@github.com:envyr-lang/envyr.git"));
+
+        let remote = get_remote("hg+https://hg.example.com/tools/deploy");
+        assert_eq!(remote, "https://hg.example.com/tools/deploy");
+        assert_eq!(
+            get_hg_storage_path(remote).unwrap(),
+            PathBuf::from("hg.example.com/tools/deploy")
+        );
+        assert_eq!(
+            get_hg_storage_path("ssh://hg@hg.example.com/tools/deploy.hg").unwrap(),
+            PathBuf::from("hg.example.com/tools/deploy")
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// Local projects, given as a path or file:// URL, and snapshots of them so edits
+// made while a package builds or runs don't affect it.
+
+use crate::envyr::package::PType;
+use crate::envyr::utils;
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Generated files kept from the previous snapshot when the source has none,
+// so unchanged projects skip regeneration.
+const GENERATED: &[&str] = &[".envyr", ".dockerignore"];
+
+// Local projects are given as a path or a `file://` URL, which is resolved
+// to its path (`file:///abs/path` or `file://localhost/abs/path`).
+pub fn local_path(url: &str) -> Result<PathBuf> {
+    let Some(rest) = url.strip_prefix("file://") else {
+        return Ok(PathBuf::from(url));
+    };
+    let path = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !path.starts_with('/') {
+        return Err(anyhow!(
+            "Unsupported file URL {}, expected file:///absolute/path.",
+            url
+        ));
+    }
+    Ok(PathBuf::from(percent_decode(path)))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Copies the project into `storage_dir_root/local/<hash of its path>/<name>`,
+// leaving out what its .dockerignore excludes, and returns the copy. The
+// directory name is kept as the detected package name derives from it.
+pub fn snapshot(source: &Path, storage_dir_root: &Path) -> Result<PathBuf> {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    source.hash(&mut hasher);
+    let dest = storage_dir_root
+        .join("local")
+        .join(format!("{:016x}", hasher.finish()))
+        .join(source.file_name().unwrap_or("root".as_ref()));
+    debug!("Snapshotting {} to {}", source.display(), dest.display());
+
+    std::fs::create_dir_all(&dest)?;
+    for entry in std::fs::read_dir(&dest)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if GENERATED.iter().any(|g| name == *g) && !source.join(&name).exists() {
+            continue;
+        }
+        match entry.file_type()?.is_dir() {
+            true => std::fs::remove_dir_all(entry.path())?,
+            false => std::fs::remove_file(entry.path())?,
+        }
+    }
+
+    let patterns = utils::read_dockerignore(source);
+    let walker = WalkDir::new(source)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| {
+            let relative = e.path().strip_prefix(source).unwrap_or(e.path());
+            !utils::is_dockerignored(relative, &patterns)
+        });
+    for entry in walker {
+        let entry = entry.map_err(|e| anyhow!("Error walking project directory: {:?}", e))?;
+        let relative = entry.path().strip_prefix(source)?;
+        let target = dest.join(relative);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+            // Kept, since metadata generation is skipped on unchanged manifest mtimes
+            let modified = entry.metadata()?.modified()?;
+            std::fs::File::options()
+                .write(true)
+                .open(&target)?
+                .set_modified(modified)?;
+        }
+    }
+    Ok(dest)
+}
+
+// The file a script piped with --stdin is written to: --name, with the
+// extension of --type added unless it has one, else stdin.<ext>.
+pub fn stdin_file_name(name: Option<&str>, ptype: Option<&PType>) -> Result<String> {
+    let ext = match ptype {
+        Some(PType::Python) => Some("py"),
+        Some(PType::Node) => Some("js"),
+        Some(PType::Shell) => Some("sh"),
+        Some(PType::Php) => Some("php"),
+        Some(PType::Perl) => Some("pl"),
+        Some(PType::Rust | PType::Java) => {
+            return Err(anyhow!(
+                "Rust and Java packages need a Cargo, Maven or Gradle project and can't be read from stdin."
+            ))
+        }
+        Some(PType::Other) => None,
+        None if name.is_none() => {
+            return Err(anyhow!(
+                "--stdin needs --type or --name to know what the script is, e.g. --type python."
+            ))
+        }
+        None => None,
+    };
+    let name = name.unwrap_or("stdin");
+    Ok(match ext {
+        Some(ext) if Path::new(name).extension().is_none() => format!("{}.{}", name, ext),
+        _ => name.to_string(),
+    })
+}
+
+// Writes a script piped on stdin to `storage_dir_root/stdin/<hash>/<file_name>`
+// and returns the project directory. The same script always lands in the same
+// directory, so its image is reused and a changed script gets a fresh one.
+pub fn stdin_project(storage_dir_root: &Path, file_name: &str, script: &[u8]) -> Result<PathBuf> {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (file_name, script).hash(&mut hasher);
+    let dest = storage_dir_root
+        .join("stdin")
+        .join(format!("{:016x}", hasher.finish()));
+    debug!("Writing stdin to {}", dest.join(file_name).display());
+    std::fs::create_dir_all(&dest)?;
+    let path = dest.join(file_name);
+    std::fs::write(&path, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_path() {
+        assert_eq!(local_path("./tool").unwrap(), PathBuf::from("./tool"));
+        assert_eq!(
+            local_path("file:///home/me/my%20tool").unwrap(),
+            PathBuf::from("/home/me/my tool")
+        );
+        assert_eq!(
+            local_path("file://localhost/tmp/tool").unwrap(),
+            PathBuf::from("/tmp/tool")
+        );
+        assert!(local_path("file://host/tmp/tool").is_err());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let source = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("build")).unwrap();
+        std::fs::write(source.path().join("build").join("out"), "x").unwrap();
+        std::fs::write(source.path().join("main.py"), "print(1)\n").unwrap();
+        std::fs::write(source.path().join(".dockerignore"), "build\n").unwrap();
+
+        let dest = snapshot(source.path(), store.path()).unwrap();
+        assert!(dest.join("main.py").exists());
+        assert!(!dest.join("build").exists());
+        assert_eq!(dest.file_name(), source.path().file_name());
+
+        // Edits only show up in the next snapshot
+        std::fs::write(source.path().join("main.py"), "print(2)\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("main.py")).unwrap(),
+            "print(1)\n"
+        );
+        std::fs::remove_file(source.path().join("main.py")).unwrap();
+        std::fs::create_dir(dest.join(".envyr")).unwrap();
+        assert_eq!(snapshot(source.path(), store.path()).unwrap(), dest);
+        assert!(!dest.join("main.py").exists());
+        assert!(dest.join(".envyr").exists());
+    }
+
+    #[test]
+    fn test_stdin_project() {
+        assert_eq!(
+            stdin_file_name(None, Some(&PType::Python)).unwrap(),
+            "stdin.py"
+        );
+        assert_eq!(
+            stdin_file_name(Some("report"), Some(&PType::Node)).unwrap(),
+            "report.js"
+        );
+        assert_eq!(
+            stdin_file_name(Some("report.mjs"), Some(&PType::Node)).unwrap(),
+            "report.mjs"
+        );
+        assert_eq!(stdin_file_name(Some("tool"), None).unwrap(), "tool");
+        assert!(stdin_file_name(None, None).is_err());
+        assert!(stdin_file_name(None, Some(&PType::Rust)).is_err());
+
+        let store = tempfile::tempdir().unwrap();
+        let dir = stdin_project(store.path(), "stdin.py", b"print(1)\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("stdin.py")).unwrap(),
+            "print(1)\n"
+        );
+        assert_eq!(
+            stdin_project(store.path(), "stdin.py", b"print(1)\n").unwrap(),
+            dir
+        );
+        assert_ne!(
+            stdin_project(store.path(), "stdin.py", b"print(2)\n").unwrap(),
+            dir
+        );
+    }
+}
@@ -1,2 +1,5 @@
 pub mod fetcher;
 pub mod git;
+pub mod hg;
+pub mod local;
+pub mod zip;
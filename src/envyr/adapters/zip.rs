@@ -0,0 +1,298 @@
+// This adapter allows using .zip archives, local or over http(s), as a source for scripts.
+
+use super::super::error::EnvyrError;
+use super::super::utils;
+use super::fetcher::{FetchOpts, Fetcher};
+use anyhow::{anyhow, Result};
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+pub struct ZipFetcher {
+    storage_dir_root: PathBuf,
+    opts: FetchOpts,
+}
+
+impl ZipFetcher {
+    pub fn new(storage_dir_root: PathBuf, opts: FetchOpts) -> Result<Self> {
+        if !storage_dir_root.exists() {
+            std::fs::create_dir_all(&storage_dir_root)?;
+        }
+        Ok(Self {
+            storage_dir_root,
+            opts,
+        })
+    }
+
+    fn download(&self, url: &str, dest: &Path) -> Result<()> {
+        if let Some(local) = url.strip_prefix("file://") {
+            std::fs::copy(local, dest)
+                .map_err(|e| anyhow!("Failed to read archive {}: {}", local, e))?;
+            return Ok(());
+        }
+        debug!("Downloading archive: {}", url);
+        // curl reads https_proxy/HTTPS_PROXY and NO_PROXY itself
+        let mut curl = std::process::Command::new("curl");
+        curl.args(["-fsSL", "--retry"])
+            .arg(self.opts.fetch_retries.saturating_sub(1).to_string());
+        if let Some(proxy) = &self.opts.proxy {
+            curl.arg("--proxy").arg(proxy);
+        }
+        let out = utils::output_until(curl.arg("-o").arg(dest).arg(url), self.opts.deadline)?;
+        if !out.status.success() {
+            return Err(anyhow!(
+                "Failed to download archive {}: {}",
+                url,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Fetcher for ZipFetcher {
+    fn fetch(&self, url: &str, tag: &str, refresh: bool) -> Result<PathBuf, EnvyrError> {
+        let (url, sha256) = split_integrity(url);
+        if tag != "latest" {
+            debug!("Archives have no tags, ignoring: {}", tag);
+        }
+        let path = self
+            .storage_dir_root
+            .join(get_zip_storage_path(url, sha256));
+        if path.exists() && !refresh {
+            debug!("Archive already extracted: {:?}", path);
+            return Ok(project_dir(&path)?);
+        }
+
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let archive = path.with_extension("zip");
+        self.download(url, &archive)?;
+        if let Some(expected) = sha256 {
+            let actual = sha256_file(&archive)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                _ = std::fs::remove_file(&archive);
+                return Err(EnvyrError::FetchFailed(format!(
+                    "Integrity check failed for {}: expected sha256 {}, got {}.",
+                    url, expected, actual
+                )));
+            }
+        }
+
+        // Extracted next to the cache and moved into place, so a failed
+        // extraction never leaves a partial project behind.
+        let staging = path.with_extension("partial");
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        let extracted = extract(&archive, &staging);
+        _ = std::fs::remove_file(&archive);
+        if let Err(e) = extracted {
+            _ = std::fs::remove_dir_all(&staging);
+            return Err(e.into());
+        }
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::rename(&staging, &path)?;
+        Ok(project_dir(&path)?)
+    }
+}
+
+// Archives are given as `http(s)://` or `file://` URLs ending in `.zip`,
+// optionally followed by `#sha256=<hex>`.
+pub fn is_zip_url(url: &str) -> bool {
+    let (url, _) = split_integrity(url);
+    let remote = ["http://", "https://", "file://"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme));
+    remote && url.to_lowercase().ends_with(".zip")
+}
+
+fn split_integrity(url: &str) -> (&str, Option<&str>) {
+    match url.rsplit_once("#sha256=") {
+        Some((url, sha256)) => (url, Some(sha256)),
+        None => (url, None),
+    }
+}
+
+// Archives are cached per URL and expected checksum. The key is a sha256,
+// which unlike std's hasher stays the same across Rust releases.
+fn get_zip_storage_path(url: &str, sha256: Option<&str>) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(url);
+    if let Some(sha256) = sha256 {
+        hasher.update(format!("#sha256={}", sha256.to_lowercase()));
+    }
+    let key = hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let name = url
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(".zip")
+        .trim_end_matches(".ZIP");
+    PathBuf::from("zip").join(format!("{}-{}", name, key))
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+fn extract(archive: &Path, dest: &Path) -> Result<()> {
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(archive)?)
+        .map_err(|e| anyhow!("Failed to open archive: {}", e))?;
+    std::fs::create_dir_all(dest)?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let target = dest.join(safe_entry_path(entry.name())?);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        if entry.is_symlink() {
+            return Err(anyhow!(
+                "Refusing to extract symlink '{}' from archive.",
+                entry.name()
+            ));
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = std::fs::File::create(&target)?;
+        std::io::copy(&mut entry, &mut out)?;
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(
+                &target,
+                std::fs::Permissions::from_mode(0o644 | (mode & 0o111)),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Normalizes an entry name, rejecting any that would land outside the
+// extraction root (zip-slip).
+fn safe_entry_path(name: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir if path.pop() => {}
+            _ => {
+                return Err(anyhow!(
+                    "Refusing to extract '{}', it escapes the extraction directory.",
+                    name
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+// Archives wrapping everything in a single top-level directory (as GitHub's
+// do) run from inside it.
+fn project_dir(path: &Path) -> Result<PathBuf> {
+    let entries = std::fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+    match entries.as_slice() {
+        [only] if only.file_type()?.is_dir() => Ok(only.path()),
+        _ => Ok(path.to_path_buf()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_zip(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_safe_entry_path() {
+        assert_eq!(
+            safe_entry_path("tool/./bin/../main.py").unwrap(),
+            PathBuf::from("tool/main.py")
+        );
+        assert!(safe_entry_path("../evil.sh").is_err());
+        assert!(safe_entry_path("tool/../../evil.sh").is_err());
+        assert!(safe_entry_path("/etc/passwd").is_err());
+        assert!(safe_entry_path("..\\evil.sh").is_err());
+    }
+
+    #[test]
+    fn test_is_zip_url() {
+        assert!(is_zip_url("https://example.com/tool.zip"));
+        assert!(is_zip_url("file:///tmp/tool.zip#sha256=abcd"));
+        assert!(!is_zip_url("/tmp/tool.zip"));
+        assert!(!is_zip_url("https://github.com/org/tool.git"));
+    }
+
+    #[test]
+    fn test_zip_storage_path() {
+        // Fixed values, the cache must survive toolchain upgrades
+        assert_eq!(
+            get_zip_storage_path("https://example.com/tool.zip", None),
+            PathBuf::from("zip/tool-c0bf5c0886f6b15d")
+        );
+        assert_eq!(
+            get_zip_storage_path("https://example.com/tool.zip", Some("ABCD")),
+            PathBuf::from("zip/tool-88116572b942b206")
+        );
+    }
+
+    #[test]
+    fn test_fetch_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("tool.zip");
+        write_zip(&archive, &[("tool/main.py", "print(1)\n")]);
+        let fetcher = ZipFetcher::new(dir.path().join("store"), FetchOpts::default()).unwrap();
+        let url = format!("file://{}", archive.display());
+
+        let path = fetcher.fetch(&url, "latest", false).unwrap();
+        assert!(path.ends_with("tool"));
+        assert!(path.join("main.py").exists());
+
+        let wrong = format!("{}#sha256={}", url, "0".repeat(64));
+        let err = fetcher.fetch(&wrong, "latest", false).unwrap_err();
+        assert!(
+            err.to_string().contains("Integrity check failed"),
+            "{}",
+            err
+        );
+
+        let evil = dir.path().join("evil.zip");
+        write_zip(&evil, &[("../evil.sh", "echo\n")]);
+        let url = format!("file://{}", evil.display());
+        assert!(fetcher.fetch(&url, "latest", false).is_err());
+        assert!(!dir.path().join("store").join("evil.sh").exists());
+    }
+}
@@ -0,0 +1,201 @@
+// Per-project configuration read from an optional envyr.toml at the project root.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::package::PType;
+
+pub const CONFIG_FILE: &str = "envyr.toml";
+
+// User-wide settings, read from an optional config.toml in the envyr root.
+pub const USER_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub name: Option<String>,
+    pub interpreter: Option<String>,
+    pub entrypoint: Option<PathBuf>,
+    pub ptype: Option<PType>,
+    pub base_image: Option<String>,
+    #[serde(default)]
+    pub env_map: Vec<String>,
+    #[serde(default)]
+    pub fs_map: Vec<String>,
+    #[serde(default)]
+    pub port_map: Vec<String>,
+    // Named entrypoints, e.g. `serve = "serve.py"`, run with `envyr run <project> -- serve`.
+    #[serde(default)]
+    pub commands: BTreeMap<String, PathBuf>,
+}
+
+impl ProjectConfig {
+    // Returns the default (empty) config if the project has no envyr.toml.
+    pub fn load(project_root: &Path) -> Result<Self> {
+        load_toml(&project_root.join(CONFIG_FILE))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    // Run sources fetched into the envyr root without network access,
+    // unless --allow-network is given.
+    #[serde(default)]
+    pub isolate_remote: bool,
+    // Branches tried in order for `latest` when a git remote has no HEAD,
+    // instead of main and master.
+    #[serde(default)]
+    pub fallback_branches: Vec<String>,
+}
+
+impl UserConfig {
+    pub fn load(envyr_root: &Path) -> Result<Self> {
+        load_toml(&envyr_root.join(USER_CONFIG_FILE))
+    }
+}
+
+// Fetched sources, snapshots and stdin scripts can always be fetched again,
+// so they go under $XDG_CACHE_HOME/envyr when it is set. Aliases, runs and
+// the config stay in the envyr root, which also holds the cache otherwise.
+pub fn cache_root(envyr_root: &Path) -> PathBuf {
+    xdg_cache_root(envyr_root, std::env::var_os("XDG_CACHE_HOME"))
+}
+
+fn xdg_cache_root(envyr_root: &Path, xdg_cache_home: Option<OsString>) -> PathBuf {
+    match xdg_cache_home.map(PathBuf::from) {
+        // Relative paths are invalid per the XDG spec and ignored
+        Some(cache_home) if cache_home.is_absolute() => cache_home.join("envyr"),
+        _ => envyr_root.to_path_buf(),
+    }
+}
+
+fn load_toml<T: DeserializeOwned + Default>(config_file: &Path) -> Result<T> {
+    if !config_file.exists() {
+        return Ok(T::default());
+    }
+    let contents = std::fs::read_to_string(config_file)?;
+    toml::from_str(&contents)
+        .map_err(|e| anyhow!("Invalid {}: {}", config_file.display(), e.message()))
+}
+
+// Merges map entries from the project config with the ones given on the CLI.
+// CLI entries win over config entries with the same key.
+pub fn merge_maps(config: &[String], cli: &[String], key: fn(&str) -> &str) -> Vec<String> {
+    config
+        .iter()
+        .filter(|c| !cli.iter().any(|o| key(o) == key(c)))
+        .chain(cli.iter())
+        .cloned()
+        .collect()
+}
+
+// KEY=value -> KEY
+pub fn env_map_key(entry: &str) -> &str {
+    entry.split('=').next().unwrap_or(entry)
+}
+
+// host_dir:container_dir -> container_dir
+pub fn fs_map_key(entry: &str) -> &str {
+    entry.split(':').nth(1).unwrap_or(entry)
+}
+
+// [ip:]host_port:container_port -> [ip:]host_port
+pub fn port_map_key(entry: &str) -> &str {
+    entry
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(entry)
+}
+
+// name:ip -> name
+pub fn add_host_key(entry: &str) -> &str {
+    entry.split(':').next().unwrap_or(entry)
+}
+
+// container_path:host_path -> host_path
+pub fn copy_out_key(entry: &str) -> &str {
+    entry.split_once(':').map_or(entry, |(_, host)| host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ProjectConfig::load(dir.path()).unwrap().name.is_none());
+
+        std::fs::write(
+            dir.path().join(CONFIG_FILE),
+            r#"
+name = "tool"
+ptype = "python"
+entrypoint = "cli/main.py"
+base_image = "python:3.12-alpine"
+env_map = ["DEBUG=1"]
+"#,
+        )
+        .unwrap();
+        let config = ProjectConfig::load(dir.path()).unwrap();
+        assert_eq!(config.name.as_deref(), Some("tool"));
+        assert!(matches!(config.ptype, Some(PType::Python)));
+        assert_eq!(config.entrypoint, Some(PathBuf::from("cli/main.py")));
+        assert_eq!(config.base_image.as_deref(), Some("python:3.12-alpine"));
+        assert_eq!(config.env_map, ["DEBUG=1"]);
+
+        std::fs::write(dir.path().join(CONFIG_FILE), "entry_point = \"x\"").unwrap();
+        let err = ProjectConfig::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("unknown field `entry_point`"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_user_config() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!UserConfig::load(dir.path()).unwrap().isolate_remote);
+        std::fs::write(dir.path().join(USER_CONFIG_FILE), "isolate_remote = true").unwrap();
+        assert!(UserConfig::load(dir.path()).unwrap().isolate_remote);
+    }
+
+    #[test]
+    fn test_xdg_cache_root() {
+        let root = Path::new("/home/me/.envyr");
+        assert_eq!(xdg_cache_root(root, None), root);
+        assert_eq!(
+            xdg_cache_root(root, Some("/home/me/.cache".into())),
+            Path::new("/home/me/.cache/envyr")
+        );
+        assert_eq!(xdg_cache_root(root, Some("cache".into())), root);
+        assert_eq!(xdg_cache_root(root, Some("".into())), root);
+    }
+
+    #[test]
+    fn test_merge_maps() {
+        let config = vec!["DEBUG=1".to_string(), "REGION=eu".to_string()];
+        let cli = vec!["DEBUG=0".to_string()];
+        assert_eq!(
+            merge_maps(&config, &cli, env_map_key),
+            ["REGION=eu", "DEBUG=0"]
+        );
+
+        let config = ["/data:/data".to_string()];
+        let cli = ["/tmp:/data".to_string()];
+        assert_eq!(merge_maps(&config, &cli, fs_map_key), ["/tmp:/data"]);
+
+        let config = ["8080:80".to_string()];
+        let cli = ["8080:8000".to_string()];
+        assert_eq!(merge_maps(&config, &cli, port_map_key), ["8080:8000"]);
+
+        let config = ["target/app:./app".to_string()];
+        let cli = ["target/app-musl:./app".to_string()];
+        assert_eq!(
+            merge_maps(&config, &cli, copy_out_key),
+            ["target/app-musl:./app"]
+        );
+    }
+}
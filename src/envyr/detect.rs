@@ -0,0 +1,233 @@
+// Project type detection. Each language has a Detector, and a project gets
+// the type of the most confident one. New languages only add a detector.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::package::PType;
+
+// A marker file settles the type, whatever the sources look like.
+pub const CONFIDENCE_MARKER: u8 = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Detection {
+    pub ptype: PType,
+    pub confidence: u8,
+    // The files the type was told from, relative to the project.
+    pub marker_files: Vec<PathBuf>,
+}
+
+pub trait Detector: Send + Sync {
+    fn ptype(&self) -> PType;
+
+    // Used when neither a shebang nor an override names the interpreter.
+    fn default_interpreter(&self) -> Option<&str>;
+
+    // `files` are the project's files relative to its root, in walk order.
+    fn detect(&self, project_root: &Path, files: &[PathBuf]) -> Option<Detection>;
+}
+
+// Detects a language by its manifests at the project root, else by the
+// extensions of its sources, the more files the more confident.
+struct Language {
+    ptype: PType,
+    markers: &'static [&'static str],
+    extensions: &'static [&'static str],
+    interpreter: Option<&'static str>,
+}
+
+impl Detector for Language {
+    fn ptype(&self) -> PType {
+        self.ptype.clone()
+    }
+
+    fn default_interpreter(&self) -> Option<&str> {
+        self.interpreter
+    }
+
+    fn detect(&self, project_root: &Path, files: &[PathBuf]) -> Option<Detection> {
+        let detection = |confidence, marker_files| Detection {
+            ptype: self.ptype(),
+            confidence,
+            marker_files,
+        };
+        let markers = self
+            .markers
+            .iter()
+            .map(PathBuf::from)
+            .filter(|m| project_root.join(m).exists())
+            .collect::<Vec<_>>();
+        if !markers.is_empty() {
+            return Some(detection(CONFIDENCE_MARKER, markers));
+        }
+        let sources = files
+            .iter()
+            .filter(|f| {
+                f.extension()
+                    .is_some_and(|ext| self.extensions.iter().any(|e| ext == *e))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        match sources.len() {
+            0 => None,
+            n => Some(detection(
+                n.min(CONFIDENCE_MARKER as usize - 1) as u8,
+                sources,
+            )),
+        }
+    }
+}
+
+// In order of precedence, for projects several detectors are equally sure of.
+// Node and Python come first, so their manifests keep winning over the rest.
+pub fn detectors() -> Vec<Box<dyn Detector>> {
+    vec![
+        Box::new(Language {
+            ptype: PType::Node,
+            markers: &["package.json"],
+            extensions: &["js", "ts"],
+            interpreter: Some("/usr/bin/env node"),
+        }),
+        Box::new(Language {
+            ptype: PType::Python,
+            markers: &["requirements.txt"],
+            extensions: &["py"],
+            interpreter: Some("/usr/bin/env python"),
+        }),
+        Box::new(Language {
+            ptype: PType::Rust,
+            markers: &["Cargo.toml"],
+            extensions: &[],
+            // Only used natively, images run the built binary directly
+            interpreter: Some("cargo run --release"),
+        }),
+        Box::new(Language {
+            ptype: PType::Java,
+            markers: &["pom.xml", "build.gradle", "build.gradle.kts"],
+            extensions: &[],
+            interpreter: Some("/usr/bin/env java"),
+        }),
+        Box::new(Language {
+            ptype: PType::Php,
+            markers: &["composer.json"],
+            extensions: &["php"],
+            interpreter: Some("/usr/bin/env php"),
+        }),
+        Box::new(Language {
+            ptype: PType::Perl,
+            markers: &["cpanfile"],
+            extensions: &["pl", "pm"],
+            interpreter: Some("/usr/bin/env perl"),
+        }),
+        Box::new(Language {
+            ptype: PType::Shell,
+            markers: &[],
+            extensions: &["sh"],
+            interpreter: Some("/bin/sh"),
+        }),
+    ]
+}
+
+// The most confident detection, the earliest detector winning ties.
+pub fn detect(project_root: &Path, files: &[PathBuf]) -> Option<Detection> {
+    let mut best: Option<Detection> = None;
+    for detection in detectors()
+        .iter()
+        .filter_map(|d| d.detect(project_root, files))
+    {
+        if best
+            .as_ref()
+            .is_none_or(|b| detection.confidence > b.confidence)
+        {
+            best = Some(detection);
+        }
+    }
+    best
+}
+
+pub fn default_interpreter(ptype: &PType) -> Option<String> {
+    detectors()
+        .iter()
+        .find(|d| std::mem::discriminant(&d.ptype()) == std::mem::discriminant(ptype))
+        .and_then(|d| d.default_interpreter().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(files: &[&str]) -> (tempfile::TempDir, Vec<PathBuf>) {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        (dir, files.iter().map(PathBuf::from).collect())
+    }
+
+    #[test]
+    fn test_detect_python() {
+        let (dir, files) = project(&["main.py", "requirements.txt"]);
+        let detection = detect(dir.path(), &files).unwrap();
+        assert!(matches!(detection.ptype, PType::Python));
+        assert_eq!(detection.confidence, CONFIDENCE_MARKER);
+        assert_eq!(detection.marker_files, [PathBuf::from("requirements.txt")]);
+
+        // Without a manifest, the sources tell
+        let (dir, files) = project(&["a.py", "b.py", "run.sh"]);
+        let detection = detect(dir.path(), &files).unwrap();
+        assert!(matches!(detection.ptype, PType::Python));
+        assert_eq!(detection.confidence, 2);
+    }
+
+    #[test]
+    fn test_detect_node() {
+        // A manifest beats any number of other sources
+        let (dir, files) = project(&["package.json", "a.py", "b.py", "index.ts"]);
+        let detection = detect(dir.path(), &files).unwrap();
+        assert!(matches!(detection.ptype, PType::Node));
+        assert_eq!(detection.marker_files, [PathBuf::from("package.json")]);
+
+        // Ties go to the earlier detector
+        let (dir, files) = project(&["package.json", "requirements.txt"]);
+        assert!(matches!(
+            detect(dir.path(), &files).unwrap().ptype,
+            PType::Node
+        ));
+    }
+
+    #[test]
+    fn test_detect_python_over_rust() {
+        // e.g. a Python package with a Rust extension
+        let (dir, files) = project(&["requirements.txt", "Cargo.toml", "main.py"]);
+        assert!(matches!(
+            detect(dir.path(), &files).unwrap().ptype,
+            PType::Python
+        ));
+
+        let (dir, files) = project(&["Cargo.toml", "build.rs"]);
+        assert!(matches!(
+            detect(dir.path(), &files).unwrap().ptype,
+            PType::Rust
+        ));
+    }
+
+    #[test]
+    fn test_detect_shell() {
+        let (dir, files) = project(&["deploy.sh", "lib.sh", "README.md"]);
+        let detection = detect(dir.path(), &files).unwrap();
+        assert!(matches!(detection.ptype, PType::Shell));
+        assert_eq!(
+            default_interpreter(&PType::Shell).as_deref(),
+            Some("/bin/sh")
+        );
+
+        let (dir, files) = project(&["README.md"]);
+        assert!(detect(dir.path(), &files).is_none());
+        assert_eq!(default_interpreter(&PType::Other), None);
+        assert_eq!(
+            default_interpreter(&PType::Node).as_deref(),
+            Some("/usr/bin/env node")
+        );
+    }
+}
@@ -1,153 +1,1133 @@
 use std::env;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
 use std::path::Path;
+use std::sync::OnceLock;
 use std::time::Instant;
 
 use anyhow::Result;
 use handlebars::Handlebars;
 use log::debug;
 use log::log_enabled;
+use log::trace;
 use serde::Deserialize;
 use serde::Serialize;
 use subprocess::{Popen, PopenConfig};
 
-use super::templates::{DOCKER_IGNORE, TEMPLATE_DOCKERFILE};
+use super::error::EnvyrError;
+use super::templates;
 
 use super::package::{PType, Pack};
 use super::utils;
 
-pub fn check_docker() -> Result<()> {
-    let mut p = Popen::create(
-        &["docker", "ps"],
-        PopenConfig {
-            stdout: subprocess::Redirection::Pipe,
-            stderr: subprocess::Redirection::Pipe,
-            ..Default::default()
-        },
-    )?;
-    p.wait_timeout(std::time::Duration::from_secs(5))?;
-    Ok(())
+// How long to wait on `<executor> ps` when probing for an executor.
+// ENVYR_PROBE_TIMEOUT_MS overrides it.
+const PROBE_TIMEOUT_MS: u64 = 1500;
+
+// Builds still running after this are stopped.
+const BUILD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+// How a package is built and run, gathered from the command line, an alias
+// and envyr.toml by the caller.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub tag: String,
+    // The checked out commit with --tag-from-git, tags the image instead.
+    pub revision: Option<String>,
+    pub refresh: bool,
+    pub no_cache: bool,
+    pub pull: bool,
+    pub cache_from: Option<String>,
+    pub cache_to: Option<String>,
+    pub secrets: Vec<String>,
+    pub registry_auth: bool,
+    pub verify_entrypoint: bool,
+    pub copy_out: Vec<String>,
+    pub interactive: bool,
+    pub no_interactive: bool,
+    pub fs_map: Vec<String>,
+    pub port_map: Vec<String>,
+    pub env_map: Vec<String>,
+    pub interpolate_env: bool,
+    pub add_host: Vec<String>,
+    pub runtime_flag: Vec<String>,
+    pub container_name: Option<String>,
+    pub keep_container: bool,
+    pub log_file: Option<String>,
+    pub mount_cwd: bool,
+    pub workdir: Option<String>,
+    pub podman_userns: bool,
+    pub memory: Option<String>,
+    pub cpus: Option<f64>,
+    pub no_network: bool,
+    pub interpreter_args: Vec<String>,
+    pub args: Vec<String>,
+    // The args were given after a leading `--`, so none names a command.
+    pub literal_args: bool,
+    pub deadline: Option<Instant>,
+    pub dry_run: bool,
+    pub quiet: bool,
+    pub shell: bool,
 }
 
-pub fn check_podman() -> Result<()> {
+impl RunOptions {
+    pub fn image_tag(&self) -> String {
+        image_tag(&self.tag, self.revision.as_deref())
+    }
+}
+
+// The image tag: the short commit SHA with --tag-from-git, else the tag.
+pub fn image_tag(tag: &str, revision: Option<&str>) -> String {
+    match revision {
+        Some(revision) => revision.chars().take(12).collect(),
+        None => tag.to_string(),
+    }
+}
+
+// Where the project lives in the image. Entrypoints are absolute under it so
+// they still resolve when the container starts in another directory.
+const APP_DIR: &str = "/envyr/app";
+
+fn probe_timeout() -> std::time::Duration {
+    let ms = env::var("ENVYR_PROBE_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(PROBE_TIMEOUT_MS);
+    std::time::Duration::from_millis(ms)
+}
+
+// Probes an executor with `ps`. A probe still running after the timeout
+// counts as unavailable, a hung daemon would only fail later in the build.
+fn check_executor(executor: &str) -> Result<(), EnvyrError> {
+    if !utils::on_path(executor) {
+        return Err(EnvyrError::ExecutorNotFound(format!(
+            "{} is not on PATH",
+            executor
+        )));
+    }
+    let probe_failed = |e: subprocess::PopenError| {
+        EnvyrError::ExecutorNotFound(format!("`{} ps` failed: {}", executor, e))
+    };
     let mut p = Popen::create(
-        &["podman", "ps"],
+        &[executor, "ps"],
         PopenConfig {
             stdout: subprocess::Redirection::Pipe,
             stderr: subprocess::Redirection::Pipe,
             ..Default::default()
         },
-    )?;
-    p.wait_timeout(std::time::Duration::from_secs(5))?;
-    Ok(())
+    )
+    .map_err(probe_failed)?;
+    match p.wait_timeout(probe_timeout()).map_err(probe_failed)? {
+        Some(status) if !status.success() => Err(EnvyrError::ExecutorNotFound(format!(
+            "`{} ps` failed, is its daemon/service running?",
+            executor
+        ))),
+        Some(_) => Ok(()),
+        None => {
+            p.kill()?;
+            p.wait().map_err(probe_failed)?;
+            Err(EnvyrError::ExecutorNotFound(format!(
+                "`{} ps` did not answer within {}ms, is its daemon hung? Raise ENVYR_PROBE_TIMEOUT_MS if it is only slow",
+                executor,
+                probe_timeout().as_millis()
+            )))
+        }
+    }
+}
+
+pub fn check_docker() -> Result<(), EnvyrError> {
+    check_executor("docker")
 }
 
-pub fn get_docker_executor() -> Result<String> {
-    if check_docker().is_ok() {
-        return Ok("docker".to_string());
-    } else if check_podman().is_ok() {
-        return Ok("podman".to_string());
+pub fn check_podman() -> Result<(), EnvyrError> {
+    check_executor("podman")
+}
+
+// The executor is probed once per process and shared by every step of a run.
+static EXECUTOR: OnceLock<String> = OnceLock::new();
+
+pub fn get_docker_executor() -> Result<String, EnvyrError> {
+    if let Some(executor) = EXECUTOR.get() {
+        return Ok(executor.clone());
+    }
+    let executor = detect_docker_executor()?;
+    Ok(EXECUTOR.get_or_init(|| executor).clone())
+}
+
+fn detect_docker_executor() -> Result<String, EnvyrError> {
+    let docker = match check_docker() {
+        Ok(()) => return Ok("docker".to_string()),
+        Err(e) => e,
+    };
+    let podman = match check_podman() {
+        Ok(()) => return Ok("podman".to_string()),
+        Err(e) => e,
+    };
+    Err(EnvyrError::ExecutorNotFound(format!(
+        "Neither docker nor podman is available ({}; {}). Install Docker (https://docs.docker.com/get-docker/) or Podman (https://podman.io/docs/installation).",
+        docker,
+        podman
+    )))
+}
+
+// Builds the image unless it exists and no rebuild is asked for, returning its name.
+pub fn build(project_root: &Path, config: &RunOptions) -> Result<String, EnvyrError> {
+    let executor = get_docker_executor()?;
+
+    // Check if the image already exists
+    let mut image = get_image_name(project_root, config.image_tag())?;
+
+    if config.refresh
+        || config.no_cache
+        || config.pull
+        || !check_image_existence(&executor, &image)?
+    {
+        // Manifests shared from a parent directory are passed as a separate build context
+        // A hand-written Dockerfile may come without meta.json, a broken one is reported.
+        let pack = match project_root.join(".envyr").join("meta.json").exists() {
+            true => Some(Pack::load(project_root)?),
+            false => None,
+        };
+        let deps_context = pack
+            .and_then(|pack| pack.deps_root)
+            .map(|deps_root| project_root.join(deps_root));
+        if config.dry_run {
+            println!(
+                "{}",
+                utils::shell_join(&get_build_command(
+                    &executor,
+                    project_root,
+                    &image,
+                    deps_context.as_deref(),
+                    config
+                ))
+            );
+        } else {
+            // rebuild
+            debug!("Building image: {}", image);
+            image = build_local(project_root, deps_context.as_deref(), config)?;
+        }
     }
-    Err(anyhow::anyhow!("Docker or Podman not found."))
+    Ok(image)
 }
 
+// Replaces the image's entrypoint with a shell, bash when available.
+fn shell_entrypoint(image: String) -> Vec<String> {
+    vec![
+        "--entrypoint".to_string(),
+        "/bin/sh".to_string(),
+        image,
+        "-c".to_string(),
+        "command -v bash >/dev/null && exec bash || exec sh".to_string(),
+    ]
+}
+
+// Builds the image if needed and runs it, returning the container's exit code.
 pub fn run(
     project_root: &Path,
-    force_rebuild: bool,
-    interactive: bool,
-    tag: String,
-    fs_map: Vec<String>,
-    port_map: Vec<String>,
-    env_map: Vec<String>,
-    args: Vec<String>,
+    config: &RunOptions,
     start: Instant,
-) -> Result<()> {
+    timings: &mut utils::Timings,
+) -> Result<i32, EnvyrError> {
+    let build_start = Instant::now();
+    let image = build(project_root, config)?;
+    copy_out(&image, config)?;
+    verify_entrypoint(&image, config)?;
+    timings.build_ms = build_start.elapsed().as_millis();
     let executor = get_docker_executor()?;
+    let pack = Pack::load(project_root).ok();
 
-    // Check if the image already exists
-    let mut image = get_image_name(project_root, tag.clone())?;
-
-    if force_rebuild || !check_image_existence(&image)? {
-        // rebuild
-        debug!("Building image: {}", image);
-        image = build_local(project_root, tag)?;
+    // A known name lets an interrupted run stop the container itself.
+    let container_name =
+        get_container_name(&executor, &pack_name(pack.as_ref(), project_root), config)?;
+    let mut command = vec![
+        executor.clone(),
+        "run".to_string(),
+        "--name".to_string(),
+        container_name.clone(),
+    ];
+    let tty = use_tty(
+        config.interactive,
+        config.no_interactive,
+        std::io::stdin().is_terminal(),
+        std::io::stdout().is_terminal(),
+    );
+    if tty || config.shell {
+        command.push("-it".to_string());
+    }
+    // Rootless podman maps the host user into the container, and SELinux
+    // needs volumes relabeled before the container can read them.
+    let podman_userns = config.podman_userns && executor == "podman";
+    let relabel = podman_userns && utils::selinux_enabled();
+    if podman_userns {
+        command.push("--userns=keep-id".to_string());
+    } else if config.podman_userns {
+        debug!("--podman-userns has no effect with {}", executor);
+    }
+    command.extend(get_port_map_args(&config.port_map));
+    command.extend(get_fs_map_args(&config.fs_map, relabel));
+    for host in &config.add_host {
+        command.push(format!("--add-host={}", host));
+    }
+    if config.mount_cwd {
+        let workdir = config.workdir.clone().unwrap_or("/workspace".to_string());
+        let cwd = env::current_dir()?;
+        command.push("-v".to_string());
+        command.push(volume(format!("{}:{}", cwd.display(), workdir), relabel));
+        command.push("-w".to_string());
+        command.push(workdir);
+    } else if let Some(workdir) = &config.workdir {
+        command.push("-w".to_string());
+        command.push(workdir.clone());
+    }
+    command.extend(get_env_map_args(&config.env_map, config.interpolate_env));
+    if config.no_network {
+        command.push("--network=none".to_string());
     }
+    if let Some(memory) = &config.memory {
+        command.push(format!("--memory={}", memory));
+    }
+    if let Some(cpus) = config.cpus {
+        command.push(format!("--cpus={}", cpus));
+    }
+    command.extend(config.runtime_flag.iter().cloned());
+    if !config.keep_container {
+        command.push("--rm".to_string());
+    }
+    let (script, mut args) = match &pack {
+        Some(pack) if !config.literal_args => named_command(&pack.commands, &config.args),
+        _ => (None, config.args.as_slice()),
+    };
+    if config.shell {
+        command.extend(shell_entrypoint(image));
+        args = &[];
+    } else if config.interpreter_args.is_empty() && script.is_none() {
+        command.push(image);
+    } else {
+        // Interpreter flags go between the image's interpreter and script,
+        // so its entrypoint is replaced with the split up original.
+        let mut entrypoint = get_image_entrypoint(&executor, &image)?;
+        if let Some(script) = script {
+            match pop_script(&mut entrypoint).first().map(String::as_str) {
+                Some("--") => {
+                    return Err(anyhow::anyhow!(
+                    "The package runs `npm start`, which can't run the script of a named command."
+                )
+                    .into())
+                }
+                Some("-jar") => entrypoint.push("-jar".to_string()),
+                _ => {}
+            }
+            entrypoint.push(
+                Path::new(APP_DIR)
+                    .join(script)
+                    .to_string_lossy()
+                    .to_string(),
+            );
+        }
+        let (program, args) = with_interpreter_args(&entrypoint, &config.interpreter_args)?;
+        command.push("--entrypoint".to_string());
+        command.push(program);
+        command.push(image);
+        command.extend(args);
+    }
+    command.extend(args.iter().cloned());
 
-    let mut interactive_mode = "";
-    if interactive {
-        interactive_mode = "-it";
+    if config.dry_run {
+        println!("{}", utils::shell_join(&command));
+        return Ok(0);
     }
 
-    let command = format!(
-        "{} run {} {} {} {} --rm {} {}",
-        executor,
-        interactive_mode,
-        get_port_map_str(port_map),
-        get_fs_map_str(fs_map),
-        get_env_map_str(env_map),
-        image,
-        args.join(" ")
-    );
-    debug!("Running command: {}", command);
+    debug!("Running command: {:?}", redact_command(&command));
     debug!("Time Elapsed in Setup: {:?}", start.elapsed());
-    let mut p = Popen::create(
-        command.split_whitespace().collect::<Vec<&str>>().as_slice(),
-        PopenConfig::default(),
+    // The log file is opened first so a bad path fails before the run
+    let log = match config.log_file.as_deref() {
+        Some("-") | None => None,
+        Some(path) => Some(
+            std::fs::File::create(path)
+                .map_err(|e| anyhow::anyhow!("Failed to create the log file {}: {}", path, e))?,
+        ),
+    };
+    let received = utils::register_interrupts()?;
+    let run_start = Instant::now();
+    let mut p = match &config.log_file {
+        Some(_) => Popen::create(
+            &command,
+            PopenConfig {
+                stdout: subprocess::Redirection::Pipe,
+                stderr: subprocess::Redirection::Merge,
+                ..Default::default()
+            },
+        )
+        .map_err(anyhow::Error::from)?,
+        None => Popen::create(&command, PopenConfig::default()).map_err(anyhow::Error::from)?,
+    };
+    // Shown on the terminal as well, or only there with `--log-file -`
+    let show = log.is_none() || std::io::stdout().is_terminal();
+    let tee = p
+        .stdout
+        .take()
+        .map(|out| std::thread::spawn(move || tee_output(out, log, show)));
+    let status = utils::wait_forwarding_interrupts(
+        &mut p,
+        &received,
+        std::time::Duration::from_secs(10),
+        config.deadline,
+        || {
+            debug!("Stopping container: {}", container_name);
+            _ = std::process::Command::new(&executor)
+                .args(["stop", "-t", "0", &container_name])
+                .output();
+        },
     )?;
-    let status = p.wait()?;
+    if let Some(Err(e)) = tee.map(|t| t.join().unwrap_or(Ok(()))) {
+        // The run itself went ahead, only its log is incomplete
+        eprintln!("Failed to write the container output: {}", e);
+    }
+    timings.run_ms = run_start.elapsed().as_millis();
     if !status.success() {
-        return Err(anyhow::anyhow!("Non-zero exit code"));
+        debug!("Container exited with: {:?}", status);
+    }
+    if config.keep_container && !config.quiet {
+        eprintln!(
+            "Kept container {name}. Inspect it with `{executor} logs {name}` or `{executor} cp {name}:<path> .`, remove it with `{executor} rm {name}`.",
+            name = container_name,
+            executor = executor
+        );
+    }
+    Ok(utils::exit_code(status))
+}
+
+// `-it` fails when envyr is piped, so a tty is only allocated when both ends
+// are terminals, unless forced either way.
+fn use_tty(interactive: bool, no_interactive: bool, stdin_tty: bool, stdout_tty: bool) -> bool {
+    match (interactive, no_interactive) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => stdin_tty && stdout_tty,
+    }
+}
+
+// Builds the image if needed, tags it for the registry and pushes it,
+// returning the pushed name.
+pub fn push(
+    project_root: &Path,
+    config: &RunOptions,
+    registry: &str,
+    image_name: Option<&str>,
+) -> Result<String, EnvyrError> {
+    let image = build(project_root, config)?;
+    let executor = get_docker_executor()?;
+    let name = image_name
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| pack_name(Pack::load(project_root).ok().as_ref(), project_root));
+    let remote = get_pushable_image_name(registry, &name, &config.image_tag());
+    let tag = [&executor, "tag", &image, &remote].map(String::from);
+    let push = [&executor, "push", &remote].map(String::from);
+    if config.dry_run {
+        println!("{}", utils::shell_join(&tag));
+        println!("{}", utils::shell_join(&push));
+        return Ok(remote);
+    }
+
+    debug!("Tagging {} as {}", image, remote);
+    let out = std::process::Command::new(&executor)
+        .args(&tag[1..])
+        .output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to tag {} as {}: {}",
+            image,
+            remote,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    // Progress goes to the terminal, errors are kept to explain them.
+    let out = std::process::Command::new(&executor)
+        .args(&push[1..])
+        .stdout(std::process::Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        if is_auth_error(&stderr) {
+            let host = registry.split('/').next().unwrap_or(registry);
+            return Err(anyhow::anyhow!(
+                "Not authorized to push to {}. Log in with `{} login {}` first: {}",
+                registry,
+                executor,
+                host,
+                stderr.trim()
+            )
+            .into());
+        }
+        return Err(anyhow::anyhow!("Failed to push {}: {}", remote, stderr.trim()).into());
+    }
+    Ok(remote)
+}
+
+fn is_auth_error(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "unauthorized",
+        "denied",
+        "authentication required",
+        "no basic auth",
+    ]
+    .iter()
+    .any(|e| stderr.contains(e))
+}
+
+// The named commands from the package's meta.json, if it has one.
+// A first arg naming one of the package's commands runs its script instead,
+// with the rest of the args.
+fn named_command<'a>(
+    commands: &std::collections::BTreeMap<String, std::path::PathBuf>,
+    args: &'a [String],
+) -> (Option<std::path::PathBuf>, &'a [String]) {
+    if commands.is_empty() {
+        return (None, args);
+    }
+    match args.split_first() {
+        Some((name, rest)) if commands.contains_key(name) => (commands.get(name).cloned(), rest),
+        _ => (None, args),
+    }
+}
+
+fn get_image_entrypoint(executor: &str, image: &str) -> Result<Vec<String>> {
+    let out = std::process::Command::new(executor)
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{json .Config.Entrypoint}}",
+            image,
+        ])
+        .output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!(
+            "Could not read the entrypoint of {}, it needs to be built first: {}",
+            image,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    let entrypoint: Option<Vec<String>> = serde_json::from_slice(&out.stdout)?;
+    Ok(entrypoint.unwrap_or_default())
+}
+
+// Splits `[interpreter, pre_args.., script]` into the program and its
+// arguments, with the interpreter args placed before the script.
+fn with_interpreter_args(
+    entrypoint: &[String],
+    interpreter_args: &[String],
+) -> Result<(String, Vec<String>)> {
+    let Some((program, rest)) = entrypoint.split_first() else {
+        return Err(anyhow::anyhow!(
+            "The image has no entrypoint to pass interpreter args to."
+        ));
+    };
+    let mut args = rest.to_vec();
+    let script = pop_script(&mut args);
+    args.extend(interpreter_args.iter().cloned());
+    args.extend(script);
+    Ok((program.clone(), args))
+}
+
+// Removes what an entrypoint runs from its end: the script, `-m module` or
+// `-jar app.jar`. `npm start` runs no script, only its closing `--` goes.
+fn pop_script(entrypoint: &mut Vec<String>) -> Vec<String> {
+    let n = match entrypoint.iter().rev().nth(1) {
+        Some(flag) if flag == "-m" || flag == "-jar" => 2,
+        _ => 1,
+    };
+    entrypoint.split_off(entrypoint.len().saturating_sub(n))
+}
+
+// Checks the map formats up front so malformed entries fail before any fetch
+// or build with an error pointing at the offending entry.
+pub fn validate_maps(
+    fs_map: &[String],
+    port_map: &[String],
+    env_map: &[String],
+) -> Result<(), EnvyrError> {
+    for entry in fs_map {
+        validate_pair(entry, "--fs-map", "host_dir:container_dir[:mode]", |f| {
+            f[2].split(',').all(|o| MOUNT_OPTIONS.contains(&o))
+        })?;
+    }
+    for entry in port_map {
+        validate_pair(
+            entry,
+            "--port-map",
+            "[host_ip:]host_port:container_port",
+            |f| f[0].parse::<std::net::IpAddr>().is_ok(),
+        )?;
+    }
+    for entry in env_map {
+        let key = entry.split('=').next().unwrap_or_default();
+        let mut chars = key.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(anyhow::anyhow!(
+                "Invalid --env-map entry '{}': expected KEY or KEY=VALUE where KEY is a valid shell identifier.",
+                entry
+            ).into());
+        }
     }
     Ok(())
 }
 
-fn get_env_map_str(env_map: Vec<String>) -> String {
-    if env_map.is_empty() {
-        return "".to_string();
+// Loosely checks a memory limit such as 512m or 2g, leaving the rest to the executor.
+pub fn parse_memory(memory: &str) -> Result<String, String> {
+    let digits = memory.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = &memory[digits.len()..];
+    if digits.is_empty()
+        || !digits.chars().all(|c| c.is_ascii_digit())
+        || !["", "b", "k", "m", "g"].contains(&unit.to_lowercase().as_str())
+    {
+        return Err(format!(
+            "'{}' is not a memory size, expected a number with an optional b/k/m/g unit, e.g. 512m or 2g.",
+            memory
+        ));
     }
-    let env_map = env_map
-        .iter()
-        .map(|x| {
-            if x.contains('=') {
-                x.to_string()
-            } else {
-                let val = env::var(x).unwrap_or("".to_string());
-                format!("{}={}", x, val)
+    Ok(memory.to_string())
+}
+
+// Checks a host entry such as db.local:10.0.0.5. Docker's special
+// host-gateway is accepted in place of the ip.
+pub fn parse_add_host(entry: &str) -> Result<String, String> {
+    let valid = entry.split_once(':').is_some_and(|(name, ip)| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            && (ip == "host-gateway" || ip.parse::<std::net::IpAddr>().is_ok())
+    });
+    if !valid {
+        return Err(format!(
+            "'{}' is not a host entry, expected name:ip, e.g. db.local:10.0.0.5.",
+            entry
+        ));
+    }
+    Ok(entry.to_string())
+}
+
+pub fn parse_copy_out(entry: &str) -> Result<String, String> {
+    match entry.split_once(':') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(entry.to_string()),
+        _ => Err(format!(
+            "'{}' is not a copy-out entry, expected container_path:host_path, e.g. target/release/app:./app.",
+            entry
+        )),
+    }
+}
+
+// Checks that the program the image's entrypoint starts is in the image, so
+// e.g. python on a plain alpine base fails here rather than with exit 127.
+pub fn verify_entrypoint(image: &str, config: &RunOptions) -> Result<(), EnvyrError> {
+    // Nothing is built on a dry run, and --shell replaces the entrypoint
+    if !config.verify_entrypoint || config.dry_run || config.shell {
+        return Ok(());
+    }
+    let executor = get_docker_executor()?;
+    let entrypoint = get_image_entrypoint(&executor, image)?;
+    let Some(program) = entrypoint_program(&entrypoint) else {
+        return Err(anyhow::anyhow!("{} has no entrypoint to verify.", image).into());
+    };
+    // dash and busybox exit 127 for a missing program, like the executor does
+    // when it can't start sh at all, so a miss exits 1 instead
+    let out = std::process::Command::new(&executor)
+        .args(["run", "--rm", "--entrypoint", "/bin/sh", image])
+        .args(["-c", "command -v \"$1\" || exit 1", "sh", program])
+        .output()?;
+    match entrypoint_check(out.status.code()) {
+        EntrypointCheck::Found => {
+            debug!(
+                "Entrypoint {} found at {}",
+                program,
+                String::from_utf8_lossy(&out.stdout).trim()
+            );
+            Ok(())
+        }
+        EntrypointCheck::Missing => Err(anyhow::anyhow!(
+            "The entrypoint's program `{}` is not in the image {}. Use a base image that has it (base_image in envyr.toml), install it with --dep, or choose another with --interpreter.",
+            program,
+            image
+        ).into()),
+        EntrypointCheck::NoShell => {
+            if !config.quiet {
+                eprintln!(
+                    "Could not verify the entrypoint of {}, it has no /bin/sh.",
+                    image
+                );
             }
+            Ok(())
+        }
+        EntrypointCheck::Failed => Err(anyhow::anyhow!(
+            "Failed to verify the entrypoint of {}: {}",
+            image,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ).into()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum EntrypointCheck {
+    Found,
+    Missing,
+    NoShell,
+    Failed,
+}
+
+// Maps the exit code of the `command -v` probe. 126/127 come from the
+// executor failing to start /bin/sh in the image, e.g. on distroless ones.
+fn entrypoint_check(code: Option<i32>) -> EntrypointCheck {
+    match code {
+        Some(0) => EntrypointCheck::Found,
+        Some(1) => EntrypointCheck::Missing,
+        Some(126 | 127) => EntrypointCheck::NoShell,
+        _ => EntrypointCheck::Failed,
+    }
+}
+
+// The program an entrypoint runs, looking through `env`.
+fn entrypoint_program(entrypoint: &[String]) -> Option<&str> {
+    let (first, rest) = entrypoint.split_first()?;
+    if first != "env" && !first.ends_with("/env") {
+        return Some(first);
+    }
+    rest.iter()
+        .map(String::as_str)
+        .find(|word| !word.starts_with('-') && !word.contains('='))
+}
+
+// Copies the --copy-out paths out of the image through a container that is
+// created, but never started, and removed afterwards.
+pub fn copy_out(image: &str, config: &RunOptions) -> Result<(), EnvyrError> {
+    if config.copy_out.is_empty() {
+        return Ok(());
+    }
+    let executor = get_docker_executor()?;
+    let container = format!("envyr-copy-out-{}", std::process::id());
+    let create = [&executor, "create", "--name", &container, image].map(String::from);
+    let remove = [&executor, "rm", "-f", &container].map(String::from);
+    let copies = config
+        .copy_out
+        .iter()
+        .map(|entry| {
+            let (from, to) = entry.split_once(':').unwrap_or((entry, "."));
+            let from = Path::new(APP_DIR).join(from);
+            vec![
+                executor.clone(),
+                "cp".to_string(),
+                format!("{}:{}", container, from.display()),
+                to.to_string(),
+            ]
         })
-        .collect::<Vec<String>>();
+        .collect::<Vec<_>>();
+    if config.dry_run {
+        println!("{}", utils::shell_join(&create));
+        for copy in &copies {
+            println!("{}", utils::shell_join(copy));
+        }
+        println!("{}", utils::shell_join(&remove));
+        return Ok(());
+    }
+
+    let output = |command: &[String]| -> Result<()> {
+        debug!("Running command: {:?}", command);
+        let out = std::process::Command::new(&command[0])
+            .args(&command[1..])
+            .output()?;
+        if !out.status.success() {
+            return Err(anyhow::anyhow!(
+                "`{}` failed: {}",
+                utils::shell_join(command),
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(())
+    };
+    output(&create)?;
+    let copied = copies.iter().try_for_each(|copy| output(copy));
+    _ = std::process::Command::new(&executor)
+        .args(&remove[1..])
+        .output();
+    Ok(copied?)
+}
+
+// Checks an id=NAME,src=PATH build secret. The file itself is read by the
+// executor at build time.
+pub fn parse_secret(entry: &str) -> Result<String, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not a build secret, expected id=NAME,src=PATH, e.g. id=npmrc,src=$HOME/.npmrc.",
+            entry
+        )
+    };
+    let mut id = None;
+    let mut src = None;
+    for part in entry.split(',') {
+        match part.split_once('=').ok_or_else(invalid)? {
+            ("id", v) => id = Some(v),
+            ("src" | "source", v) => src = Some(v),
+            _ => return Err(invalid()),
+        }
+    }
+    let valid_id = id.is_some_and(|id| {
+        !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    });
+    if !valid_id || src.is_none_or(|src| src.is_empty()) {
+        return Err(invalid());
+    }
+    Ok(entry.to_string())
+}
 
-    let env_map_string = String::from("-e");
-    format!("{} {}", env_map_string, env_map.join(" -e "))
+// id=NAME,src=PATH -> NAME
+pub fn secret_id(entry: &str) -> &str {
+    entry
+        .split(',')
+        .find_map(|part| part.strip_prefix("id="))
+        .unwrap_or(entry)
 }
 
-fn get_port_map_str(port_map: Vec<String>) -> String {
-    if port_map.is_empty() {
-        return "".to_string();
+pub fn parse_cpus(cpus: &str) -> Result<f64, String> {
+    match cpus.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n),
+        _ => Err(format!(
+            "'{}' is not a CPU count, expected a positive number, e.g. 1.5.",
+            cpus
+        )),
     }
-    let port_map_string = String::from("-p");
-    format!("{} {}", port_map_string, port_map.join(" -p "))
 }
 
-fn get_fs_map_str(fs_map: Vec<String>) -> String {
-    if fs_map.is_empty() {
-        return "".to_string();
+// Options a volume's mode may combine, e.g. ro,Z.
+const MOUNT_OPTIONS: &[&str] = &[
+    "ro",
+    "rw",
+    "z",
+    "Z",
+    "nocopy",
+    "shared",
+    "slave",
+    "private",
+    "rshared",
+    "rslave",
+    "rprivate",
+    "cached",
+    "delegated",
+    "consistent",
+];
+
+// Checks a colon separated map entry has two fields, or three when the
+// extra one passes `valid_third`.
+fn validate_pair(
+    entry: &str,
+    flag: &str,
+    format: &str,
+    valid_third: fn(&[&str]) -> bool,
+) -> Result<()> {
+    let fields = entry.split(':').collect::<Vec<_>>();
+    let valid = fields.iter().all(|f| !f.is_empty())
+        && match fields.len() {
+            2 => true,
+            3 => valid_third(&fields),
+            _ => false,
+        };
+    if !valid {
+        return Err(anyhow::anyhow!(
+            "Invalid {} entry '{}': expected {}.",
+            flag,
+            entry,
+            format
+        ));
     }
-    let fs_map_string = String::from("-v");
-    format!("{} {}", fs_map_string, fs_map.join(" -v "))
+    Ok(())
 }
 
+fn get_env_map_args(env_map: &[String], interpolate: bool) -> Vec<String> {
+    let pairs = env_map
+        .iter()
+        .map(|x| match x.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (x.to_string(), env::var(x).unwrap_or("".to_string())),
+        })
+        .collect::<Vec<_>>();
+    // Passed variables are looked up before the host's, each only once
+    let lookup = |name: &str| {
+        pairs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .or_else(|| env::var(name).ok())
+    };
+    pairs
+        .iter()
+        .flat_map(|(key, value)| {
+            let value = match interpolate {
+                true => interpolate_env(value, lookup),
+                false => value.clone(),
+            };
+            ["-e".to_string(), format!("{}={}", key, value)]
+        })
+        .collect()
+}
+
+// Expands $VAR and ${VAR} like a shell would, unset ones to nothing. \$ is
+// kept as a literal $.
+fn interpolate_env(value: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find(['\\', '$']) {
+        result.push_str(&rest[..i]);
+        let (c, after) = (&rest[i..i + 1], &rest[i + 1..]);
+        if c == "\\" {
+            match after.strip_prefix('$') {
+                Some(after) => {
+                    result.push('$');
+                    rest = after;
+                }
+                None => {
+                    result.push('\\');
+                    rest = after;
+                }
+            }
+            continue;
+        }
+        let (name, after) = match after.strip_prefix('{') {
+            Some(braced) => match braced.split_once('}') {
+                Some((name, after)) if !name.is_empty() && name.chars().all(is_name) => {
+                    (name, after)
+                }
+                _ => ("", after),
+            },
+            None => after.split_at(after.find(|c| !is_name(c)).unwrap_or(after.len())),
+        };
+        if name.is_empty() {
+            // Not a variable, e.g. a trailing or lone $
+            result.push('$');
+        } else {
+            result.push_str(&lookup(name).unwrap_or_default());
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
+// Masks env, build arg and secret values for logging, keeping their keys.
+// The command itself still carries the real values.
+fn redact_command(command: &[String]) -> Vec<String> {
+    const FLAGS: &[&str] = &["-e", "--env", "--build-arg", "--secret"];
+    let mask = |flag: &str, value: &str| match flag {
+        "--secret" => value
+            .split(',')
+            .map(|part| match part.split_once('=') {
+                Some(("id", _)) | None => part.to_string(),
+                Some((key, _)) => format!("{}=***", key),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => match value.split_once('=') {
+            Some((key, _)) => format!("{}=***", key),
+            None => value.to_string(),
+        },
+    };
+    let mut redacted = Vec::with_capacity(command.len());
+    let mut pending: Option<&str> = None;
+    for arg in command {
+        if let Some(flag) = pending.take() {
+            redacted.push(mask(flag, arg));
+            continue;
+        }
+        if let Some(flag) = FLAGS.iter().find(|f| *f == arg) {
+            pending = Some(flag);
+            redacted.push(arg.clone());
+        } else if let Some((flag, value)) =
+            arg.split_once('=').filter(|(flag, _)| FLAGS.contains(flag))
+        {
+            redacted.push(format!("{}={}", flag, mask(flag, value)));
+        } else {
+            redacted.push(arg.clone());
+        }
+    }
+    redacted
+}
+
+fn get_port_map_args(port_map: &[String]) -> Vec<String> {
+    port_map
+        .iter()
+        .flat_map(|x| ["-p".to_string(), x.to_string()])
+        .collect()
+}
+
+fn get_fs_map_args(fs_map: &[String], relabel: bool) -> Vec<String> {
+    fs_map
+        .iter()
+        .flat_map(|x| ["-v".to_string(), volume(x.to_string(), relabel)])
+        .collect()
+}
+
+// Adds the private SELinux label to a volume mount if asked to, next to
+// any mode it already has.
+fn volume(mount: String, relabel: bool) -> String {
+    match (relabel, mount.matches(':').count()) {
+        (false, _) => mount,
+        (true, 1) => format!("{}:Z", mount),
+        (true, _) => format!("{},Z", mount),
+    }
+}
+
+// All images built by envyr are named with this prefix.
+const IMAGE_PREFIX: &str = "envyr";
+
 fn get_image_name(project_root: &Path, tag: String) -> Result<String> {
     let mut name_str = String::from(project_root.to_str().unwrap());
     name_str = name_str.replace(['/', '.'], "-");
     Ok(format!(
-        "envyr{}:{}",
+        "{}{}:{}",
+        IMAGE_PREFIX,
         name_str.to_lowercase(),
         tag.to_lowercase()
     ))
 }
 
-fn check_image_existence(image: &str) -> Result<bool> {
+// Container names are derived from the pack name and tag unless given. Derived
+// names get a numeric suffix when taken, given ones are an error.
+fn get_container_name(executor: &str, pack_name: &str, config: &RunOptions) -> Result<String> {
+    if let Some(name) = &config.container_name {
+        if !config.dry_run && container_names(executor)?.contains(name) {
+            return Err(anyhow::anyhow!(
+                "Container name '{}' is already in use. Pick another with --container-name.",
+                name
+            ));
+        }
+        return Ok(name.clone());
+    }
+    let name = default_container_name(pack_name, &config.image_tag());
+    if config.dry_run {
+        return Ok(name);
+    }
+    free_container_name(&name, &container_names(executor)?).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Could not find a free container name for '{}'. Pick one with --container-name.",
+            name
+        )
+    })
+}
+
+// The name, or the first of name-2, name-3, ... that isn't taken.
+fn free_container_name(name: &str, taken: &[String]) -> Option<String> {
+    (1..100)
+        .map(|n| match n {
+            1 => name.to_string(),
+            _ => format!("{}-{}", name, n),
+        })
+        .find(|candidate| !taken.contains(candidate))
+}
+
+// The package name, falling back to the directory name without metadata.
+fn pack_name(pack: Option<&Pack>, project_root: &Path) -> String {
+    match pack {
+        Some(pack) => pack.name.clone(),
+        None => project_root
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    }
+}
+
+fn default_container_name(pack_name: &str, tag: &str) -> String {
+    sanitize_name(&format!("{}-{}-{}", IMAGE_PREFIX, pack_name, tag))
+}
+
+// Lowercases a name and replaces anything docker rejects in names with `-`.
+pub fn sanitize_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+// The name an image is pushed to a registry under, e.g. ghcr.io/me/tool:1.0.
+pub fn get_pushable_image_name(registry: &str, name: &str, tag: &str) -> String {
+    format!(
+        "{}/{}:{}",
+        registry.trim_end_matches('/'),
+        sanitize_name(name),
+        tag
+    )
+}
+
+// The names of all containers, running or not.
+fn container_names(executor: &str) -> Result<Vec<String>> {
+    let out = std::process::Command::new(executor)
+        .args(["ps", "-a", "--format", "{{.Names}}"])
+        .output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to list containers: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    pub name: String,
+    pub tag: String,
+    pub size: String,
+    pub created: String,
+}
+
+// Lists the images built by envyr on this machine.
+pub fn list_images() -> Result<Vec<ImageInfo>, EnvyrError> {
     let executor = get_docker_executor()?;
+    let cmd = std::process::Command::new(executor)
+        .arg("images")
+        .arg("--filter")
+        .arg(format!("reference={}*", IMAGE_PREFIX))
+        .arg("--format")
+        .arg("{{.Repository}}\t{{.Tag}}\t{{.Size}}\t{{.CreatedAt}}")
+        .output()?;
+    if !cmd.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to list images: {}",
+            String::from_utf8_lossy(&cmd.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&cmd.stdout)
+        .lines()
+        .filter_map(parse_image_line)
+        .collect())
+}
+
+fn parse_image_line(line: &str) -> Option<ImageInfo> {
+    let mut fields = line.split('\t');
+    Some(ImageInfo {
+        name: fields.next()?.to_string(),
+        tag: fields.next()?.to_string(),
+        size: fields.next()?.to_string(),
+        created: fields.next()?.to_string(),
+    })
+}
+
+fn check_image_existence(executor: &str, image: &str) -> Result<bool> {
     let cmd = std::process::Command::new(executor)
         .arg("images")
         .arg("-q")
@@ -163,91 +1143,516 @@ fn check_image_existence(image: &str) -> Result<bool> {
     Ok(false)
 }
 
-fn build_local(project_root: &Path, tag: String) -> Result<String> {
+fn build_local(
+    project_root: &Path,
+    deps_context: Option<&Path>,
+    config: &RunOptions,
+) -> Result<String, EnvyrError> {
     let executor = get_docker_executor()?;
 
-    let image = get_image_name(project_root, tag)?;
+    let image = get_image_name(project_root, config.image_tag())?;
+    if config.cache_from.is_some() || config.cache_to.is_some() {
+        check_buildkit(&executor, "--cache-from/--cache-to")?;
+    }
+    if !config.secrets.is_empty() {
+        check_buildkit(&executor, "--secret")?;
+    }
+    if deps_context.is_some() {
+        check_buildkit(&executor, "Manifests outside the project (--build-context)")?;
+    }
 
-    let dockerfile_path = project_root.join(".envyr").join("Dockerfile");
     debug!("Building local docker image: {}", image);
     let mut popen_conf = PopenConfig {
         stdout: subprocess::Redirection::Pipe,
-        stderr: subprocess::Redirection::Pipe,
+        stderr: subprocess::Redirection::Merge,
         ..Default::default()
     };
     if log_enabled!(log::Level::Debug) {
-        // This prints all logs
-        popen_conf = PopenConfig::default();
+        // This prints all logs, to stderr along with envyr's own
+        popen_conf = PopenConfig {
+            stdout: subprocess::Redirection::Merge,
+            ..Default::default()
+        };
     }
-    let mut p = Popen::create(
-        &[
-            executor.as_str(),
-            "build",
-            "-t",
-            image.as_str(),
-            "-f",
-            dockerfile_path.to_str().unwrap(),
-            project_root.to_str().unwrap(),
-        ],
-        popen_conf,
-    )?;
-    let status = p.wait_timeout(std::time::Duration::from_secs(300))?;
+    let registries = std::fs::read_to_string(project_root.join(".envyr").join("Dockerfile"))
+        .map(|dockerfile| base_image_registries(&dockerfile))
+        .unwrap_or_default();
+    if config.registry_auth {
+        if let Some(registry) = registries.iter().find(|r| !is_logged_in(&executor, r)) {
+            return Err(not_authenticated(&executor, registry));
+        }
+    }
+    let build_failed = |e: subprocess::PopenError| {
+        EnvyrError::BuildFailed(format!("Failed to build docker image: {}", e))
+    };
+    let command = get_build_command(&executor, project_root, &image, deps_context, config);
+    debug!("Build command: {:?}", redact_command(&command));
+    let build_env = get_build_env(&command);
+    if !build_env.is_empty() {
+        let mut env = PopenConfig::current_env();
+        env.extend(build_env.into_iter().map(|(k, v)| (k.into(), v.into())));
+        popen_conf.env = Some(env);
+    }
+    let mut p = Popen::create(&command, popen_conf).map_err(build_failed)?;
+    // Follow the captured output on a separate thread so the timeout still applies.
+    let progress = p.stdout.take().map(|out| {
+        let show = !config.quiet && std::io::stderr().is_terminal();
+        std::thread::spawn(move || follow_build(out, show))
+    });
+    // The build also stops at the --total-timeout deadline, if sooner
+    let remaining = config
+        .deadline
+        .map(|d| d.saturating_duration_since(Instant::now()));
+    let timeout = remaining.map_or(BUILD_TIMEOUT, |r| r.min(BUILD_TIMEOUT));
+    let status = p.wait_timeout(timeout).map_err(build_failed)?;
+    if status.is_none() {
+        p.kill()?;
+        p.wait().map_err(build_failed)?;
+    }
+    let logs = match progress {
+        Some(handle) => handle.join().unwrap_or_default(),
+        None => vec![],
+    };
 
     match status {
-        Some(s) => {
-            if s.success() {
-                Ok(image)
-            } else {
-                Err(anyhow::anyhow!("Failed to build docker image."))
+        Some(s) if s.success() => Ok(image),
+        Some(_) => {
+            eprintln!("{}", logs.join("\n"));
+            // A pull refused for want of a login is the likely cause
+            if logs.iter().any(|l| is_auth_error(l)) {
+                if let Some(registry) = registries.iter().find(|r| !is_logged_in(&executor, r)) {
+                    return Err(not_authenticated(&executor, registry));
+                }
             }
+            Err(EnvyrError::BuildFailed(
+                "Failed to build docker image.".to_string(),
+            ))
+        }
+        None => {
+            eprintln!("{}", logs.join("\n"));
+            Err(EnvyrError::Timeout(match timeout < BUILD_TIMEOUT {
+                true => "--total-timeout expired while building the docker image.".to_string(),
+                false => format!(
+                    "Building the docker image took longer than {}s.",
+                    BUILD_TIMEOUT.as_secs()
+                ),
+            }))
         }
-        None => Err(anyhow::anyhow!("Failed to build docker image.")),
     }
 }
 
-pub fn generate_dockerfile(pack: &Pack, project_root: &Path) -> Result<String> {
+// External build caches and secrets need BuildKit, which docker only has
+// through buildx. Podman's build supports them natively.
+fn check_buildkit(executor: &str, flags: &str) -> Result<(), EnvyrError> {
+    if executor != "docker" {
+        return Ok(());
+    }
+    let buildx = std::process::Command::new(executor)
+        .args(["buildx", "version"])
+        .output()
+        .is_ok_and(|out| out.status.success());
+    if !buildx || env::var("DOCKER_BUILDKIT").is_ok_and(|v| v == "0") {
+        return Err(EnvyrError::BuildFailed(format!(
+            "{} need BuildKit. Install the docker buildx plugin and unset DOCKER_BUILDKIT=0.",
+            flags
+        )));
+    }
+    Ok(())
+}
+
+// Docker Hub, which images without a registry are pulled from.
+const DEFAULT_REGISTRIES: &[&str] = &["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+// The registry an image is pulled from, None for Docker Hub.
+pub fn image_registry(image: &str) -> Option<&str> {
+    let (first, _) = image.split_once('/')?;
+    let qualified = first.contains(['.', ':']) || first == "localhost";
+    (qualified && !DEFAULT_REGISTRIES.contains(&first)).then_some(first)
+}
+
+// The registries of a Dockerfile's base images, skipping its own stages.
+fn base_image_registries(dockerfile: &str) -> Vec<String> {
+    let mut stages: Vec<String> = vec![];
+    let mut registries: Vec<String> = vec![];
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        if !words.next().is_some_and(|w| w.eq_ignore_ascii_case("FROM")) {
+            continue;
+        }
+        let words = words.filter(|w| !w.starts_with("--")).collect::<Vec<_>>();
+        let Some(image) = words.first() else {
+            continue;
+        };
+        if !stages.contains(&image.to_lowercase()) {
+            if let Some(registry) = image_registry(image) {
+                if !registries.iter().any(|r| r == registry) {
+                    registries.push(registry.to_string());
+                }
+            }
+        }
+        if let [_, keyword, stage] = words[..] {
+            if keyword.eq_ignore_ascii_case("AS") {
+                stages.push(stage.to_lowercase());
+            }
+        }
+    }
+    registries
+}
+
+// Whether credentials for the registry are stored. Podman is asked directly,
+// for docker its config.json is read and any credential helper queried.
+fn is_logged_in(executor: &str, registry: &str) -> bool {
+    if executor != "docker" {
+        return std::process::Command::new(executor)
+            .args(["login", "--get-login", registry])
+            .output()
+            .is_ok_and(|out| out.status.success());
+    }
+    let config_dir = match env::var_os("DOCKER_CONFIG") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => home::home_dir().unwrap_or_default().join(".docker"),
+    };
+    let config = std::fs::read_to_string(config_dir.join("config.json"))
+        .ok()
+        .and_then(|c| serde_json::from_str::<serde_json::Value>(&c).ok())
+        .unwrap_or_default();
+    docker_credentials(&config, registry, |helper| {
+        let helper = format!("docker-credential-{}", helper);
+        let mut p = Popen::create(
+            &[helper.as_str(), "get"],
+            PopenConfig {
+                stdin: subprocess::Redirection::Pipe,
+                stdout: subprocess::Redirection::Pipe,
+                stderr: subprocess::Redirection::Pipe,
+                ..Default::default()
+            },
+        )
+        .ok()?;
+        p.communicate(Some(registry)).ok()?;
+        p.wait().ok().map(|s| s.success())
+    })
+}
+
+// Looks the registry up in a docker config.json: an inline auth, else its
+// credential helper or the default store, asked via `ask_helper`.
+fn docker_credentials(
+    config: &serde_json::Value,
+    registry: &str,
+    ask_helper: impl Fn(&str) -> Option<bool>,
+) -> bool {
+    let host = |key: &str| {
+        let key = key.split_once("://").map_or(key, |(_, rest)| rest);
+        key.split('/').next().unwrap_or(key).to_string()
+    };
+    let inline = config["auths"].as_object().is_some_and(|auths| {
+        auths
+            .iter()
+            .any(|(key, auth)| host(key) == registry && auth["auth"].is_string())
+    });
+    if inline {
+        return true;
+    }
+    let helper = config["credHelpers"][registry]
+        .as_str()
+        .or_else(|| config["credsStore"].as_str());
+    helper.and_then(ask_helper).unwrap_or(false)
+}
+
+fn not_authenticated(executor: &str, registry: &str) -> EnvyrError {
+    EnvyrError::BuildFailed(format!(
+        "Failed to build docker image: not authenticated to {}. Log in with `{} login {}` first.",
+        registry, executor, registry
+    ))
+}
+
+// Copies the container's output to the log file and/or stdout as it comes,
+// so progress output isn't held back until a line ends. A failing sink is
+// dropped but the output is still drained, so the container never blocks.
+fn tee_output(
+    mut out: std::fs::File,
+    mut log: Option<std::fs::File>,
+    mut show: bool,
+) -> std::io::Result<()> {
+    let mut buf = [0; 8192];
+    let mut stdout = std::io::stdout();
+    let mut failed = None;
+    loop {
+        let n = match out.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(Err(e)) = log.as_mut().map(|l| l.write_all(&buf[..n])) {
+            log = None;
+            failed = Some(e);
+        }
+        if show {
+            if let Err(e) = stdout.write_all(&buf[..n]).and_then(|_| stdout.flush()) {
+                show = false;
+                failed = failed.or(Some(e));
+            }
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+// Reads the build output line by line, showing a spinner with the current
+// build step if asked to. Returns the full log for reporting failures.
+fn follow_build(out: std::fs::File, show: bool) -> Vec<String> {
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+    let mut logs = vec![];
+    let mut step = String::from("Building image");
+    for line in BufReader::new(out).lines().map_while(|l| l.ok()) {
+        if let Some(current) = parse_build_step(&line) {
+            step = current;
+        }
+        if show {
+            let frame = SPINNER[logs.len() % SPINNER.len()];
+            let status: String = step.chars().take(72).collect();
+            eprint!("\r\x1b[2K{} {}", frame, status);
+        }
+        logs.push(line);
+    }
+    if show {
+        eprint!("\r\x1b[2K");
+    }
+    logs
+}
+
+// Extracts the current step from docker, buildkit and podman build output.
+fn parse_build_step(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.starts_with("Step ") || line.starts_with("STEP ") {
+        return Some(line.to_string());
+    }
+    if line.starts_with('#') {
+        let idx = line.find(" [")?;
+        return Some(line[idx + 1..].to_string());
+    }
+    None
+}
+
+// Flags the legacy builder rejects.
+const BUILDKIT_FLAGS: &[&str] = &["--secret", "--build-context", "--cache-from", "--cache-to"];
+
+// Older docker releases only use BuildKit for `docker build` when asked to,
+// so it is asked for whenever the command has a BuildKit-only flag.
+fn get_build_env(command: &[String]) -> Vec<(String, String)> {
+    let buildkit = command[0] == "docker"
+        && command.iter().any(|arg| {
+            BUILDKIT_FLAGS
+                .iter()
+                .any(|flag| arg == flag || arg.starts_with(&format!("{}=", flag)))
+        });
+    match buildkit {
+        true => vec![("DOCKER_BUILDKIT".to_string(), "1".to_string())],
+        false => vec![],
+    }
+}
+
+fn get_build_command(
+    executor: &str,
+    project_root: &Path,
+    image: &str,
+    deps_context: Option<&Path>,
+    config: &RunOptions,
+) -> Vec<String> {
+    let dockerfile_path = project_root.join(".envyr").join("Dockerfile");
+    let mut command = vec![executor.to_string(), "build".to_string()];
+    if config.no_cache {
+        command.push("--no-cache".to_string());
+    }
+    if let Some(revision) = &config.revision {
+        command.push(format!(
+            "--label=org.opencontainers.image.revision={}",
+            revision
+        ));
+    }
+    if config.pull {
+        command.push("--pull".to_string());
+    }
+    if let Some(cache_from) = &config.cache_from {
+        command.push(format!("--cache-from={}", cache_from));
+    }
+    if let Some(cache_to) = &config.cache_to {
+        command.push(format!("--cache-to={}", cache_to));
+    }
+    for secret in &config.secrets {
+        command.push(format!("--secret={}", secret));
+    }
+    if let Some(deps_context) = deps_context {
+        command.push("--build-context".to_string());
+        command.push(format!("deps={}", deps_context.display()));
+    }
+    command.extend([
+        "-t".to_string(),
+        image.to_string(),
+        "-f".to_string(),
+        dockerfile_path.to_str().unwrap().to_string(),
+        project_root.to_str().unwrap().to_string(),
+    ]);
+    command
+}
+
+pub fn generate_dockerfile(pack: &Pack, project_root: &Path) -> Result<String, EnvyrError> {
     let mut handlebars = Handlebars::new();
-    let source = TEMPLATE_DOCKERFILE;
-    handlebars.register_template_string("Dockerfile", source)?;
+    let source = templates::dockerfile_template()?;
+    handlebars
+        .register_template_string("Dockerfile", source.as_ref())
+        .map_err(|e| anyhow::anyhow!("Invalid Dockerfile template: {}", e))?;
 
     #[derive(Default, Serialize, Deserialize)]
     struct Data {
         interpreter: String,
+        interpreter_args: Vec<String>,
         entrypoint: String,
+        pre_args: Vec<String>,
+        manifest_add: String,
         os_deps: Vec<String>,
         ptype: PType,
         type_reqs: bool,
+        python_installer: String,
+        composer_lock: bool,
+        typescript: bool,
+        tsconfig: bool,
+        ts_source: String,
+        rust_bin: String,
+        java_build: String,
+        module: Option<String>,
+        npm_start: bool,
+        node_installer: String,
+        package_lock: bool,
+        requirements: Vec<String>,
+        secret_mounts: String,
+        base_image: Option<String>,
     }
 
-    // trim env prefix on interpreter
-    let interpreter = pack.interpreter.trim_start_matches("/usr/bin/env ");
+    // trim env prefix on interpreter, words after the program (`python -u`)
+    // become separate ENTRYPOINT elements
+    let mut words = pack
+        .interpreter
+        .trim_start_matches("/usr/bin/env ")
+        .split_whitespace();
+    let interpreter = words.next().unwrap_or_default();
 
     let mut d = Data {
         interpreter: interpreter.to_string(),
+        interpreter_args: words
+            .map(serde_json::to_string)
+            .collect::<Result<_, _>>()
+            .map_err(anyhow::Error::from)?,
         entrypoint: pack.entrypoint.to_str().unwrap().to_string(),
+        module: utils::python_module(&pack.entrypoint).map(str::to_string),
+        npm_start: utils::is_npm_start(&pack.entrypoint),
+        // Rendered unescaped as JSON strings in the exec form ENTRYPOINT.
+        pre_args: pack
+            .pre_args
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<_, _>>()
+            .map_err(anyhow::Error::from)?,
+        // Manifests outside the project come from the `deps` build context.
+        manifest_add: match pack.deps_root {
+            Some(_) => "COPY --from=deps".to_string(),
+            None => "ADD".to_string(),
+        },
         os_deps: pack.deps.clone(),
         ptype: pack.ptype.clone(),
         type_reqs: false,
+        // Secrets are only readable by the install steps, under /run/secrets.
+        secret_mounts: pack
+            .secrets
+            .iter()
+            .map(|id| format!("--mount=type=secret,id={} ", id))
+            .collect(),
+        base_image: pack.base_image.clone(),
+        ..Default::default()
     };
 
     // Figure out type specific deps
     match d.ptype {
         PType::Python => {
-            d.type_reqs = utils::check_requirements_txt(project_root);
+            if d.base_image.is_none() {
+                if let Some(version) = utils::read_python_version(project_root) {
+                    d.base_image = Some(format!("python:{}-alpine", version));
+                }
+            }
+            let deps_dir = pack.deps_dir(project_root);
+            // Requirements files given explicitly are installed with pip.
+            let (installer, requirements) = if pack.requirements.is_empty() {
+                (
+                    utils::detect_python_installer(&deps_dir),
+                    utils::detect_requirements_files(&deps_dir),
+                )
+            } else {
+                (Some("pip".to_string()), pack.requirements.clone())
+            };
+            if let Some(installer) = installer {
+                d.type_reqs = true;
+                d.python_installer = installer;
+            }
+            d.requirements = requirements
+                .iter()
+                .map(|r| r.to_string_lossy().to_string())
+                .collect();
         }
         PType::Node => {
-            d.type_reqs = utils::check_package_json(project_root);
+            let deps_dir = pack.deps_dir(project_root);
+            d.type_reqs = utils::check_package_json(&deps_dir);
+            d.node_installer = utils::detect_node_installer(&deps_dir);
+            d.package_lock = utils::check_package_lock(&deps_dir);
+            d.tsconfig = utils::check_tsconfig_json(project_root);
+            d.typescript = d.tsconfig;
+            // A ts entrypoint is compiled first and the js output run instead.
+            if pack.entrypoint.extension().is_some_and(|e| e == "ts") {
+                d.typescript = true;
+                d.ts_source = d.entrypoint.clone();
+                d.entrypoint = utils::typescript_output(project_root, &pack.entrypoint)
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+            }
+        }
+        PType::Perl => {
+            d.type_reqs = utils::check_cpanfile(&pack.deps_dir(project_root));
+            // Use the image's perl, which has the cpanm installed modules
+            if d.interpreter.ends_with("/perl") {
+                d.interpreter = "perl".to_string();
+            }
+        }
+        PType::Php => {
+            let deps_dir = pack.deps_dir(project_root);
+            d.type_reqs = utils::check_composer_json(&deps_dir);
+            d.composer_lock = utils::check_composer_lock(&deps_dir);
+        }
+        PType::Java => {
+            d.java_build = utils::detect_java_build(project_root).unwrap_or_default();
+        }
+        PType::Rust => {
+            d.rust_bin = pack
+                .entrypoint
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
         }
         _ => {}
     };
 
-    Ok(handlebars.render("Dockerfile", &d)?)
+    let dockerfile = handlebars
+        .render("Dockerfile", &d)
+        .map_err(|e| anyhow::anyhow!("Failed to render the Dockerfile: {}", e))?;
+    trace!("Rendered Dockerfile:\n{}", dockerfile);
+    Ok(dockerfile)
 }
 
-pub fn generate_docker_ignore(pack: &Pack) -> Result<String> {
+pub fn generate_docker_ignore(pack: &Pack) -> Result<String, EnvyrError> {
     let mut handlebars = Handlebars::new();
-    let source = DOCKER_IGNORE;
-    handlebars.register_template_string("dockerignore", source)?;
+    let source = templates::dockerignore_template()?;
+    handlebars
+        .register_template_string("dockerignore", source.as_ref())
+        .map_err(|e| anyhow::anyhow!("Invalid dockerignore template: {}", e))?;
 
     #[derive(Default, Serialize, Deserialize)]
     struct Data {
@@ -258,18 +1663,695 @@ pub fn generate_docker_ignore(pack: &Pack) -> Result<String> {
         ptype: pack.ptype.clone(),
     };
 
-    Ok(handlebars.render("dockerignore", &d)?)
+    Ok(handlebars
+        .render("dockerignore", &d)
+        .map_err(|e| anyhow::anyhow!("Failed to render the dockerignore: {}", e))?)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn python_pack() -> Pack {
+        Pack {
+            version: crate::envyr::package::META_VERSION,
+            name: "app".to_string(),
+            interpreter: "/usr/bin/env python".to_string(),
+            ptype: PType::Python,
+            deps: vec![],
+            manual_deps: vec![],
+            pre_args: vec![],
+            deps_root: None,
+            base_image: None,
+            commands: Default::default(),
+            requirements: vec![],
+            secrets: vec![],
+            entrypoint: "main.py".into(),
+        }
+    }
+
+    #[test]
+    fn test_dockerfile_python_installers() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("requirements.txt"), "").unwrap();
+        let dockerfile = generate_dockerfile(&python_pack(), dir.path()).unwrap();
+        assert!(dockerfile.contains("pip install -r requirements.txt"));
+
+        std::fs::write(dir.path().join("Pipfile"), "").unwrap();
+        std::fs::write(dir.path().join("Pipfile.lock"), "").unwrap();
+        let dockerfile = generate_dockerfile(&python_pack(), dir.path()).unwrap();
+        assert!(dockerfile.contains("pipenv install --system --deploy"));
+        assert!(!dockerfile.contains("requirements.txt"));
+
+        std::fs::write(dir.path().join(".python-version"), "3.10\n").unwrap();
+        let dockerfile = generate_dockerfile(&python_pack(), dir.path()).unwrap();
+        assert!(dockerfile.contains("FROM python:3.10-alpine"));
+
+        std::fs::write(dir.path().join("pyproject.toml"), "").unwrap();
+        std::fs::write(dir.path().join("poetry.lock"), "").unwrap();
+        let dockerfile = generate_dockerfile(&python_pack(), dir.path()).unwrap();
+        assert!(dockerfile.contains("poetry install"));
+        assert!(!dockerfile.contains("pipenv"));
+    }
+
+    #[test]
+    fn test_dockerfile_requirements_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("requirements.txt"), "").unwrap();
+        std::fs::write(dir.path().join("requirements-dev.txt"), "").unwrap();
+        let dockerfile = generate_dockerfile(&python_pack(), dir.path()).unwrap();
+        assert!(dockerfile.contains("ADD ./requirements-dev.txt /envyr/app/requirements-dev.txt"));
+        assert!(dockerfile.contains("pip install -r requirements.txt -r requirements-dev.txt"));
+
+        let mut pack = python_pack();
+        pack.requirements = vec!["reqs/base.txt".into()];
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ADD ./reqs/base.txt /envyr/app/reqs/base.txt"));
+        assert!(dockerfile.contains("pip install -r reqs/base.txt\n"));
+    }
+
+    #[test]
+    fn test_dockerfile_pre_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let dockerfile = generate_dockerfile(&python_pack(), dir.path()).unwrap();
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["python", "/envyr/app/main.py"]"#));
+
+        let mut pack = python_pack();
+        pack.pre_args = vec!["-O".to_string(), "-X\"a".to_string()];
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(
+            dockerfile.contains(r#"ENTRYPOINT ["python", "-O", "-X\"a", "/envyr/app/main.py"]"#)
+        );
+
+        let mut pack = python_pack();
+        pack.interpreter = "/usr/bin/env python -u".to_string();
+        pack.pre_args = vec!["-O".to_string()];
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["python", "-u", "-O", "/envyr/app/main.py"]"#));
+    }
+
+    #[test]
+    fn test_dockerfile_deps_root() {
+        let repo = tempfile::tempdir().unwrap();
+        let app = repo.path().join("app");
+        std::fs::create_dir(&app).unwrap();
+        std::fs::write(repo.path().join("requirements.txt"), "").unwrap();
+        let mut pack = python_pack();
+        pack.deps_root = Some("..".into());
+        let dockerfile = generate_dockerfile(&pack, &app).unwrap();
+        assert!(
+            dockerfile.contains("COPY --from=deps ./requirements.txt /envyr/app/requirements.txt")
+        );
+    }
+
+    #[test]
+    fn test_dockerfile_php() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack {
+            interpreter: "/usr/bin/env php".to_string(),
+            ptype: PType::Php,
+            entrypoint: "bin/app.php".into(),
+            ..python_pack()
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("FROM php:cli-alpine"));
+        assert!(!dockerfile.contains("composer install"));
+
+        std::fs::write(dir.path().join("composer.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("composer.lock"), "{}").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ADD ./composer.json ./composer.lock /envyr/app/"));
+        assert!(dockerfile.contains("RUN composer install"));
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["php", "/envyr/app/bin/app.php"]"#));
+    }
+
+    #[test]
+    fn test_dockerfile_perl() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack {
+            interpreter: "/usr/bin/perl".to_string(),
+            ptype: PType::Perl,
+            deps: vec!["git".to_string()],
+            entrypoint: "report".into(),
+            ..python_pack()
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("FROM perl:slim"));
+        assert!(!dockerfile.contains("apk add"));
+        assert!(dockerfile
+            .contains("apt-get install -y --no-install-recommends ca-certificates bash  git"));
+        assert!(!dockerfile.contains("cpanm"));
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["perl", "/envyr/app/report"]"#));
+
+        std::fs::write(dir.path().join("cpanfile"), "").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("RUN cpanm --notest --installdeps ."));
+    }
+
+    #[test]
+    fn test_dockerfile_rust() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack {
+            name: "hello".to_string(),
+            interpreter: "cargo run --release".to_string(),
+            ptype: PType::Rust,
+            entrypoint: "target/release/hello".into(),
+            ..python_pack()
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("FROM rust:alpine AS build"));
+        assert!(dockerfile.contains("RUN cargo build --release --bin hello"));
+        assert!(dockerfile.contains("FROM alpine\n"));
+        assert!(dockerfile.contains(
+            "COPY --from=build /envyr/app/target/release/hello /envyr/app/target/release/hello"
+        ));
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["/envyr/app/target/release/hello"]"#));
+        assert!(generate_docker_ignore(&pack)
+            .unwrap()
+            .contains("\ntarget\n"));
+    }
+
+    #[test]
+    fn test_dockerfile_python_module() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pack = python_pack();
+        pack.entrypoint = "-m tool.cli".into();
+        pack.pre_args = vec!["-u".to_string()];
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ENV PYTHONPATH=/envyr/app\n"));
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["python", "-u", "-m", "tool.cli"]"#));
+        assert!(!generate_dockerfile(&python_pack(), dir.path())
+            .unwrap()
+            .contains("PYTHONPATH"));
+    }
+
+    #[test]
+    fn test_dockerfile_java() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("pom.xml"), "<project/>").unwrap();
+        let pack = Pack {
+            name: "report".to_string(),
+            interpreter: "/usr/bin/env java".to_string(),
+            ptype: PType::Java,
+            pre_args: vec!["-Xmx512m".to_string()],
+            entrypoint: "target/report-1.0.jar".into(),
+            ..python_pack()
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("FROM maven:3-eclipse-temurin-17 AS build"));
+        assert!(dockerfile.contains("RUN mvn -B -q package -DskipTests"));
+        assert!(dockerfile.contains("FROM eclipse-temurin:17-jre-alpine"));
+        assert!(dockerfile.contains(
+            "COPY --from=build /envyr/app/target/report-1.0.jar /envyr/app/target/report-1.0.jar"
+        ));
+        assert!(dockerfile.contains(
+            r#"ENTRYPOINT ["java", "-Xmx512m", "-jar", "/envyr/app/target/report-1.0.jar"]"#
+        ));
+        assert!(!dockerfile.contains("ADD . /envyr/app\nENTRYPOINT"));
+
+        std::fs::remove_file(dir.path().join("pom.xml")).unwrap();
+        std::fs::write(dir.path().join("build.gradle"), "").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("FROM gradle:jdk17 AS build"));
+        assert!(generate_docker_ignore(&pack).unwrap().contains("\nbuild\n"));
+    }
+
+    #[test]
+    fn test_entrypoint_check() {
+        assert_eq!(entrypoint_check(Some(0)), EntrypointCheck::Found);
+        // The probe exits 1 itself for a missing program
+        assert_eq!(entrypoint_check(Some(1)), EntrypointCheck::Missing);
+        assert_eq!(entrypoint_check(Some(127)), EntrypointCheck::NoShell);
+        assert_eq!(entrypoint_check(Some(125)), EntrypointCheck::Failed);
+        assert_eq!(entrypoint_check(None), EntrypointCheck::Failed);
+    }
+
+    #[test]
+    fn test_entrypoint_program() {
+        let words = |w: &[&str]| w.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            entrypoint_program(&words(&["python", "-u", "/envyr/app/main.py"])),
+            Some("python")
+        );
+        assert_eq!(
+            entrypoint_program(&words(&["/usr/bin/env", "-S", "A=1", "bash", "run.sh"])),
+            Some("bash")
+        );
+        assert_eq!(
+            entrypoint_program(&words(&["/envyr/app/target/release/tool"])),
+            Some("/envyr/app/target/release/tool")
+        );
+        assert_eq!(entrypoint_program(&[]), None);
+    }
+
+    #[test]
+    fn test_use_tty() {
+        assert!(use_tty(false, false, true, true));
+        // Piped stdin or stdout
+        assert!(!use_tty(false, false, false, true));
+        assert!(!use_tty(false, false, true, false));
+        assert!(use_tty(true, false, false, false));
+        assert!(!use_tty(false, true, true, true));
+    }
+
+    #[test]
+    fn test_with_interpreter_args() {
+        let entrypoint = ["python", "-X", "main.py"].map(String::from);
+        let (program, args) = with_interpreter_args(&entrypoint, &["-O".to_string()]).unwrap();
+        assert_eq!(program, "python");
+        assert_eq!(args, ["-X", "-O", "main.py"]);
+        let entrypoint = ["python", "-m", "tool.cli"].map(String::from);
+        let (_, args) = with_interpreter_args(&entrypoint, &["-O".to_string()]).unwrap();
+        assert_eq!(args, ["-O", "-m", "tool.cli"]);
+        let entrypoint = ["java", "-Xmx512m", "-jar", "/envyr/app/app.jar"].map(String::from);
+        let (_, args) = with_interpreter_args(&entrypoint, &["-ea".to_string()]).unwrap();
+        assert_eq!(args, ["-Xmx512m", "-ea", "-jar", "/envyr/app/app.jar"]);
+        assert!(with_interpreter_args(&[], &["-O".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_interpreter_args_npm_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack {
+            interpreter: "npm".to_string(),
+            ptype: PType::Node,
+            entrypoint: "npm start".into(),
+            ..python_pack()
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        let line = dockerfile
+            .lines()
+            .find(|l| l.starts_with("ENTRYPOINT "))
+            .unwrap();
+        let entrypoint: Vec<String> =
+            serde_json::from_str(line.trim_start_matches("ENTRYPOINT ")).unwrap();
+        // npm's own flags go before the `--` that ends them, there is no script
+        let (program, args) =
+            with_interpreter_args(&entrypoint, &["--silent".to_string()]).unwrap();
+        assert_eq!(program, "npm");
+        assert_eq!(args, ["start", "--silent", "--"]);
+    }
+
+    #[test]
+    fn test_named_command() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let commands =
+            std::collections::BTreeMap::from([("worker".to_string(), "worker.py".into())]);
+        let run = args(&["worker", "--queue", "high"]);
+        assert_eq!(
+            named_command(&commands, &run),
+            (Some("worker.py".into()), &run[1..])
+        );
+        let run = args(&["migrate"]);
+        assert_eq!(named_command(&commands, &run), (None, &run[..]));
+        let run = args(&["worker"]);
+        assert_eq!(named_command(&Default::default(), &run), (None, &run[..]));
+    }
+
+    #[test]
+    fn test_pushable_image_name() {
+        assert_eq!(
+            super::get_pushable_image_name("ghcr.io/me/", "My Tool", "v1"),
+            "ghcr.io/me/my-tool:v1"
+        );
+        assert!(super::is_auth_error(
+            "denied: requested access to the resource is denied"
+        ));
+        assert!(!super::is_auth_error("connection refused"));
+    }
+
+    #[test]
+    fn test_default_container_name() {
+        assert_eq!(
+            default_container_name("My Tool", "v1.2"),
+            "envyr-my-tool-v1.2"
+        );
+        assert_eq!(
+            default_container_name("app", "feature/x"),
+            "envyr-app-feature-x"
+        );
+
+        let taken = ["envyr-app-v1", "envyr-app-v1-2", "other"].map(String::from);
+        assert_eq!(
+            free_container_name("envyr-app-v1", &taken).as_deref(),
+            Some("envyr-app-v1-3")
+        );
+        assert_eq!(
+            free_container_name("envyr-app-v2", &taken).as_deref(),
+            Some("envyr-app-v2")
+        );
+    }
+
+    #[test]
+    fn test_dockerfile_typescript() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pack = Pack {
+            interpreter: "/usr/bin/env node".to_string(),
+            ptype: PType::Node,
+            entrypoint: "index.ts".into(),
+            ..python_pack()
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("RUN npx --yes -p typescript tsc index.ts"));
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["node", "/envyr/app/index.js"]"#));
+
+        std::fs::write(
+            dir.path().join("tsconfig.json"),
+            r#"{"compilerOptions": {"outDir": "dist", "rootDir": "src"}}"#,
+        )
+        .unwrap();
+        pack.entrypoint = "src/index.ts".into();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("RUN npx --yes -p typescript tsc\n"));
+        assert!(dockerfile.contains(r#"ENTRYPOINT ["node", "/envyr/app/dist/index.js"]"#));
+    }
+
+    #[test]
+    fn test_dockerfile_node_installers() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack {
+            interpreter: "/usr/bin/env node".to_string(),
+            ptype: PType::Node,
+            entrypoint: "index.js".into(),
+            ..python_pack()
+        };
+        std::fs::write(dir.path().join("package.json"), "{}").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ADD ./package.json /envyr/app/\nRUN npm install\n"));
+
+        std::fs::write(dir.path().join("package-lock.json"), "{}").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ADD ./package.json ./package-lock.json /envyr/app/"));
+        assert!(dockerfile.contains("RUN npm ci\n"));
+
+        std::fs::write(dir.path().join("yarn.lock"), "").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("RUN yarn install --frozen-lockfile"));
+
+        std::fs::write(dir.path().join("pnpm-lock.yaml"), "").unwrap();
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ADD ./package.json ./pnpm-lock.yaml /envyr/app/"));
+        assert!(dockerfile.contains("pnpm install --frozen-lockfile"));
+
+        let pack = Pack {
+            interpreter: "npm".to_string(),
+            entrypoint: "npm start".into(),
+            ..pack
+        };
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile.contains("ENTRYPOINT [\"npm\", \"start\", \"--\"]"));
+    }
+
+    #[test]
+    fn test_parse_build_step() {
+        assert_eq!(
+            parse_build_step("Step 2/9 : RUN apk add bash").as_deref(),
+            Some("Step 2/9 : RUN apk add bash")
+        );
+        assert_eq!(
+            parse_build_step("#6 [2/5] RUN apk add --no-cache bash").as_deref(),
+            Some("[2/5] RUN apk add --no-cache bash")
+        );
+        assert_eq!(parse_build_step("#6 DONE 0.4s"), None);
+        assert_eq!(parse_build_step(" ---> Running in 5d2b"), None);
+    }
+
+    #[test]
+    fn test_parse_image_line() {
+        let info = parse_image_line("envyr-tmp-app\tlatest\t52.1MB\t2024-01-02 10:00:00 +0000 UTC")
+            .unwrap();
+        assert_eq!(info.name, "envyr-tmp-app");
+        assert_eq!(info.tag, "latest");
+        assert_eq!(info.size, "52.1MB");
+        assert_eq!(info.created, "2024-01-02 10:00:00 +0000 UTC");
+        assert!(parse_image_line("envyr-tmp-app\tlatest").is_none());
+    }
+
+    #[test]
+    fn test_validate_maps() {
+        let strs = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert!(validate_maps(
+            &strs(&["/a:/b"]),
+            &strs(&["80:8080"]),
+            &strs(&["A_1=x", "_B"])
+        )
+        .is_ok());
+
+        let err = validate_maps(&[], &strs(&["8080"]), &[]).unwrap_err();
+        assert!(err.to_string().contains("'8080'"));
+        assert!(validate_maps(&strs(&["/a:"]), &[], &[]).is_err());
+        assert!(validate_maps(&strs(&["/a:/b:/c"]), &[], &[]).is_err());
+        assert!(validate_maps(&strs(&["/a:/b:ro", "/c:/d:ro,z"]), &[], &[]).is_ok());
+        assert!(validate_maps(&strs(&["/a:/b:"]), &[], &[]).is_err());
+        assert!(validate_maps(&[], &strs(&["127.0.0.1:8080:80"]), &[]).is_ok());
+        assert!(validate_maps(&[], &strs(&["localhost:8080:80"]), &[]).is_err());
+        assert!(validate_maps(&[], &strs(&["1.2.3.4:1:2:3"]), &[]).is_err());
+        assert!(validate_maps(&[], &[], &strs(&["1KEY=x"])).is_err());
+        assert!(validate_maps(&[], &[], &strs(&["MY-KEY"])).is_err());
+        assert!(validate_maps(&[], &[], &strs(&["=x"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(super::parse_memory("512m").unwrap(), "512m");
+        assert!(super::parse_memory("2G").is_ok());
+        assert!(super::parse_memory("1048576").is_ok());
+        assert!(super::parse_memory("m").is_err());
+        assert!(super::parse_memory("2gb").is_err());
+        assert!(super::parse_memory("1.5g").is_err());
+        assert_eq!(super::parse_cpus("1.5").unwrap(), 1.5);
+        assert!(super::parse_cpus("0").is_err());
+        assert!(super::parse_cpus("two").is_err());
+    }
+
+    #[test]
+    fn test_parse_add_host() {
+        assert!(parse_add_host("db.local:10.0.0.5").is_ok());
+        assert!(parse_add_host("api:::1").is_ok());
+        assert!(parse_add_host("host:host-gateway").is_ok());
+        assert!(parse_add_host("db.local").is_err());
+        assert!(parse_add_host(":10.0.0.5").is_err());
+        assert!(parse_add_host("db:10.0.0").is_err());
+    }
+
+    #[test]
+    fn test_parse_copy_out() {
+        assert!(parse_copy_out("target/release/app:./bin/app").is_ok());
+        assert!(parse_copy_out("/usr/local/bin/tool:.").is_ok());
+        assert!(parse_copy_out("target/release/app").is_err());
+        assert!(parse_copy_out(":./app").is_err());
+    }
+
+    #[test]
+    fn test_secrets() {
+        assert!(parse_secret("id=npmrc,src=/home/me/.npmrc").is_ok());
+        assert!(parse_secret("src=token.txt,id=pip_token").is_ok());
+        assert!(parse_secret("id=npmrc").is_err());
+        assert!(parse_secret("id=,src=token.txt").is_err());
+        assert!(parse_secret("id=a b,src=token.txt").is_err());
+        assert!(parse_secret("id=npmrc,src=x,mode=0400").is_err());
+        assert_eq!(secret_id("src=token.txt,id=pip_token"), "pip_token");
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("requirements.txt"), "").unwrap();
+        let mut pack = python_pack();
+        pack.secrets = vec!["pip_token".to_string()];
+        let dockerfile = generate_dockerfile(&pack, dir.path()).unwrap();
+        assert!(dockerfile
+            .contains("RUN --mount=type=secret,id=pip_token pip install -r requirements.txt"));
+        assert!(!generate_dockerfile(&python_pack(), dir.path())
+            .unwrap()
+            .contains("--mount"));
+    }
 
     #[test]
     fn test_docker_volumes_map() {
         let input = vec!["/root:/root".to_string()];
-        assert_eq!(super::get_fs_map_str(input), "-v /root:/root");
+        assert_eq!(super::get_fs_map_args(&input, false), ["-v", "/root:/root"]);
 
         let input = vec!["/root:/root".to_string(), ".app:/app".to_string()];
-        assert_eq!(super::get_fs_map_str(input), "-v /root:/root -v .app:/app");
+        assert_eq!(
+            super::get_fs_map_args(&input, false),
+            ["-v", "/root:/root", "-v", ".app:/app"]
+        );
+        assert_eq!(
+            super::get_fs_map_args(&input, true),
+            ["-v", "/root:/root:Z", "-v", ".app:/app:Z"]
+        );
+        let input = vec!["/root:/root:ro".to_string()];
+        assert_eq!(
+            super::get_fs_map_args(&input, true),
+            ["-v", "/root:/root:ro,Z"]
+        );
+    }
+
+    #[test]
+    fn test_docker_env_map_keeps_spaces() {
+        let input = vec!["GREETING=hello world".to_string()];
+        assert_eq!(
+            super::get_env_map_args(&input, false),
+            ["-e", "GREETING=hello world"]
+        );
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let input = vec![
+            "HOST=db.local".to_string(),
+            "URL=https://$HOST:${PORT}/x".to_string(),
+            "PRICE=\\$5 $".to_string(),
+        ];
+        // Literal unless asked for
+        assert_eq!(get_env_map_args(&input, false)[3], input[1]);
+
+        let lookup = |name: &str| match name {
+            "HOST" => Some("db.local".to_string()),
+            "PORT" => Some("5432".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            interpolate_env("https://$HOST:${PORT}/x", lookup),
+            "https://db.local:5432/x"
+        );
+        assert_eq!(
+            interpolate_env("\\$HOST costs $$ ${ } $UNSET.", lookup),
+            "$HOST costs $$ ${ } ."
+        );
+        assert_eq!(interpolate_env("C:\\dir", lookup), "C:\\dir");
+        assert_eq!(get_env_map_args(&input, true)[5], "PRICE=$5 $");
+    }
+
+    #[test]
+    fn test_base_image_registries() {
+        assert_eq!(image_registry("python:3.12-alpine"), None);
+        assert_eq!(image_registry("library/python"), None);
+        assert_eq!(image_registry("docker.io/library/python"), None);
+        assert_eq!(
+            image_registry("registry.example.com:5000/base/python:3.12"),
+            Some("registry.example.com:5000")
+        );
+        assert_eq!(image_registry("localhost/python"), Some("localhost"));
+
+        let dockerfile = "FROM --platform=linux/amd64 ghcr.io/me/builder:1 AS build\n\
+            RUN make\n\
+            from registry.example.com/python:3.12\n\
+            FROM build\n\
+            COPY --from=build /out /out\n";
+        assert_eq!(
+            base_image_registries(dockerfile),
+            ["ghcr.io", "registry.example.com"]
+        );
+
+        let config = serde_json::json!({
+            "auths": {"https://ghcr.io/v1/": {"auth": "dXNlcjpwYXNz"}, "quay.io": {}},
+            "credHelpers": {"registry.example.com": "ecr-login"},
+        });
+        let no_helper = |_: &str| -> Option<bool> { panic!("no helper expected") };
+        assert!(docker_credentials(&config, "ghcr.io", no_helper));
+        assert!(!docker_credentials(&config, "quay.io", no_helper));
+        assert!(docker_credentials(&config, "registry.example.com", |h| {
+            Some(h == "ecr-login")
+        }));
+    }
+
+    #[test]
+    fn test_tee_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = "line 1\nprogress 50%\rprogress 100%\n".repeat(1000);
+        std::fs::write(dir.path().join("out"), &output).unwrap();
+        let out = std::fs::File::open(dir.path().join("out")).unwrap();
+        let log = std::fs::File::create(dir.path().join("run.log")).unwrap();
+        tee_output(out, Some(log), false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("run.log")).unwrap(),
+            output
+        );
+    }
+
+    #[test]
+    fn test_redact_command() {
+        let mut command = vec!["docker".to_string(), "run".to_string()];
+        command.extend(super::get_env_map_args(
+            &["TOKEN=s3cret".to_string(), "EMPTY=".to_string()],
+            false,
+        ));
+        command.extend(
+            [
+                "--env=API_KEY=abc",
+                "--build-arg",
+                "NPM_TOKEN=xyz",
+                "--secret=id=npmrc,src=/home/me/.npmrc",
+                "-p",
+                "8080:80",
+                "img",
+            ]
+            .map(String::from),
+        );
+        let executed = command.clone();
+        assert_eq!(
+            redact_command(&command),
+            [
+                "docker",
+                "run",
+                "-e",
+                "TOKEN=***",
+                "-e",
+                "EMPTY=***",
+                "--env=API_KEY=***",
+                "--build-arg",
+                "NPM_TOKEN=***",
+                "--secret=id=npmrc,src=***",
+                "-p",
+                "8080:80",
+                "img",
+            ]
+        );
+        assert_eq!(command, executed);
+        assert!(command.contains(&"TOKEN=s3cret".to_string()));
+        assert!(command.contains(&"--secret=id=npmrc,src=/home/me/.npmrc".to_string()));
+    }
+
+    #[test]
+    fn test_build_command_deps_context() {
+        let config = RunOptions::default();
+        let project = Path::new("/src/app/svc");
+        let command = get_build_command("docker", project, "img", None, &config);
+        assert!(!command.contains(&"--build-context".to_string()));
+        assert!(get_build_env(&command).is_empty());
+        let mut command = get_build_command(
+            "docker",
+            project,
+            "img",
+            Some(Path::new("/src/app")),
+            &config,
+        );
+        let i = command.iter().position(|c| c == "--build-context").unwrap();
+        assert_eq!(command[i + 1], "deps=/src/app");
+        assert_eq!(
+            get_build_env(&command),
+            [("DOCKER_BUILDKIT".to_string(), "1".to_string())]
+        );
+        command[0] = "podman".to_string();
+        assert!(get_build_env(&command).is_empty());
+    }
+
+    #[test]
+    fn test_build_env_cache() {
+        let project = Path::new("/src/app");
+        let config = RunOptions {
+            cache_from: Some("type=registry,ref=ghcr.io/me/cache".to_string()),
+            ..Default::default()
+        };
+        let command = get_build_command("docker", project, "img", None, &config);
+        assert_eq!(
+            get_build_env(&command),
+            [("DOCKER_BUILDKIT".to_string(), "1".to_string())]
+        );
+        let config = RunOptions {
+            cache_to: Some("type=local,dest=/tmp/cache".to_string()),
+            ..Default::default()
+        };
+        let command = get_build_command("docker", project, "img", None, &config);
+        assert!(!get_build_env(&command).is_empty());
     }
 }
@@ -0,0 +1,19 @@
+// Failure kinds callers may want to tell apart, e.g. to retry a fetch but not
+// a failed build. Anything else is carried as `Other`.
+#[derive(Debug, thiserror::Error)]
+pub enum EnvyrError {
+    #[error("{0}")]
+    ExecutorNotFound(String),
+    #[error("{0}")]
+    BuildFailed(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    FetchFailed(String),
+    #[error("{0}")]
+    DetectionFailed(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
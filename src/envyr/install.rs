@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::debug;
+
+use super::utils;
+
+// Marks wrappers written by envyr so uninstall never removes anything else.
+static WRAPPER_MARKER: &str = "# Installed by envyr.";
+
+pub fn bin_dir(envyr_root: &Path) -> PathBuf {
+    envyr_root.join("bin")
+}
+
+fn render_wrapper(exe: &Path, alias: &str) -> String {
+    format!(
+        "#!/bin/sh\n{} Remove with `envyr uninstall {}`.\nexec {} run {} -- \"$@\"\n",
+        WRAPPER_MARKER,
+        alias,
+        utils::shell_quote(&exe.display().to_string()),
+        utils::shell_quote(alias)
+    )
+}
+
+// Writes an executable wrapper to `bin_dir/name` that runs the alias of the same name.
+pub fn install_wrapper(bin_dir: &Path, name: &str, exe: &Path) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        return Err(anyhow::anyhow!("Invalid install name '{}'.", name));
+    }
+    std::fs::create_dir_all(bin_dir)?;
+    let wrapper = bin_dir.join(name);
+    std::fs::write(&wrapper, render_wrapper(exe, name))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+    }
+    debug!("Installed wrapper at {}", wrapper.display());
+    Ok(wrapper)
+}
+
+pub fn uninstall_wrapper(bin_dir: &Path, name: &str) -> Result<PathBuf> {
+    let wrapper = bin_dir.join(name);
+    let contents = std::fs::read_to_string(&wrapper)
+        .map_err(|_| anyhow::anyhow!("'{}' is not installed.", name))?;
+    if !contents.contains(WRAPPER_MARKER) {
+        return Err(anyhow::anyhow!(
+            "{} was not installed by envyr, refusing to remove it.",
+            wrapper.display()
+        ));
+    }
+    std::fs::remove_file(&wrapper)?;
+    Ok(wrapper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_uninstall_wrapper() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = bin_dir(dir.path());
+        let wrapper = install_wrapper(&bin, "tool", Path::new("/opt/my envyr")).unwrap();
+        let contents = std::fs::read_to_string(&wrapper).unwrap();
+        assert!(contents.contains("exec '/opt/my envyr' run tool -- \"$@\""));
+
+        std::fs::write(bin.join("other"), "#!/bin/sh\n").unwrap();
+        assert!(uninstall_wrapper(&bin, "other").is_err());
+        assert!(uninstall_wrapper(&bin, "missing").is_err());
+        uninstall_wrapper(&bin, "tool").unwrap();
+        assert!(!wrapper.exists());
+        assert!(install_wrapper(&bin, "../x", Path::new("envyr")).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+// Kubernetes manifests for running packages on a cluster.
+
+use anyhow::Result;
+use handlebars::Handlebars;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use super::package::Pack;
+use super::templates::TEMPLATE_K8S_JOB;
+
+#[derive(Serialize)]
+struct EnvVar {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct Port {
+    port: String,
+    protocol: String,
+}
+
+// Renders a Job running `image`, with the run maps translated: env_map to
+// `env` and the container side of port_map to `ports`. The memory and cpus
+// limits become resource requests.
+pub fn generate_k8s_job(
+    pack: &Pack,
+    image: &str,
+    args: &[String],
+    env_map: &[String],
+    port_map: &[String],
+    resources: (Option<&str>, Option<f64>),
+) -> Result<String> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars.register_template_string("job", TEMPLATE_K8S_JOB)?;
+
+    let env = env_map
+        .iter()
+        .map(|entry| {
+            let (name, value) = match entry.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (entry.clone(), std::env::var(entry).unwrap_or_default()),
+            };
+            Ok(EnvVar {
+                name: serde_json::to_string(&name)?,
+                value: serde_json::to_string(&value)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let ports = port_map
+        .iter()
+        .filter_map(|entry| entry.rsplit(':').next())
+        .map(|container| {
+            let (port, protocol) = container.split_once('/').unwrap_or((container, "tcp"));
+            Port {
+                port: port.to_string(),
+                protocol: protocol.to_uppercase(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut requests = BTreeMap::new();
+    let (memory, cpus) = resources;
+    if let Some(memory) = memory {
+        requests.insert("memory", serde_json::to_string(&k8s_memory(memory))?);
+    }
+    if let Some(cpus) = cpus {
+        requests.insert("cpu", serde_json::to_string(&cpus.to_string())?);
+    }
+
+    let name = super::docker::sanitize_name(&pack.name)
+        .replace(['_', '.'], "-")
+        .trim_matches('-')
+        .to_string();
+    let data = serde_json::json!({
+        "name": name,
+        "image": serde_json::to_string(image)?,
+        "args": args.iter().map(serde_json::to_string).collect::<Result<Vec<_>, _>>()?,
+        "env": env,
+        "ports": ports,
+        "requests": requests,
+    });
+    Ok(handlebars.render("job", &data)?)
+}
+
+// Docker sizes use binary units, which Kubernetes spells Ki/Mi/Gi
+// (a bare `m` there means milli).
+fn k8s_memory(memory: &str) -> String {
+    let unit = memory.chars().last().map(|c| c.to_ascii_lowercase());
+    let number = &memory[..memory.len() - 1];
+    match unit {
+        Some('b') => number.to_string(),
+        Some('k') => format!("{}Ki", number),
+        Some('m') => format!("{}Mi", number),
+        Some('g') => format!("{}Gi", number),
+        _ => memory.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envyr::package::{PType, META_VERSION};
+
+    #[test]
+    fn test_generate_k8s_job() {
+        let pack = Pack {
+            version: META_VERSION,
+            name: "My_Tool".to_string(),
+            interpreter: "/usr/bin/env python".to_string(),
+            ptype: PType::Python,
+            deps: vec![],
+            manual_deps: vec![],
+            pre_args: vec![],
+            deps_root: None,
+            base_image: None,
+            commands: Default::default(),
+            requirements: vec![],
+            secrets: vec![],
+            entrypoint: "main.py".into(),
+        };
+        let job = generate_k8s_job(
+            &pack,
+            "ghcr.io/me/my_tool:1.0",
+            &["--verbose".to_string()],
+            &["REGION=eu west".to_string()],
+            &["8080:80".to_string(), "53:53/udp".to_string()],
+            (Some("512m"), Some(1.5)),
+        )
+        .unwrap();
+        assert!(job.contains("  name: my-tool\n"));
+        assert!(job.contains("restartPolicy: Never"));
+        assert!(job.contains("image: \"ghcr.io/me/my_tool:1.0\""));
+        assert!(job.contains("args: [\"--verbose\"]"));
+        assert!(job.contains("- name: \"REGION\"\n              value: \"eu west\""));
+        assert!(job.contains("- containerPort: 80\n              protocol: TCP"));
+        assert!(job.contains("- containerPort: 53\n              protocol: UDP"));
+        assert!(job.contains("cpu: \"1.5\"\n              memory: \"512Mi\""));
+    }
+}
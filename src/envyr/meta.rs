@@ -1,21 +1,119 @@
+use std::hash::{Hash, Hasher};
 use std::path::Path;
 
 use crate::RunConfig;
 
 use super::package::Pack;
-use super::{docker, utils};
+use super::{docker, templates, utils};
 use anyhow::Result;
 use clap::ValueEnum;
 use log::debug;
 use serde::{Deserialize, Serialize};
 
+// A Dockerfile starting with this line is never regenerated.
+pub const KEEP_MARKER: &str = "# envyr: keep";
+
+// A .dockerignore containing this line is never written to.
+pub const DOCKERIGNORE_UNMANAGED_MARKER: &str = "# envyr-managed: false";
+
+// Files whose changes invalidate previously generated metadata.
+const MANIFEST_FILES: &[&str] = &[
+    "requirements.txt",
+    "requirements-dev.txt",
+    "dev-requirements.txt",
+    "pyproject.toml",
+    "poetry.lock",
+    "Pipfile",
+    "Pipfile.lock",
+    ".python-version",
+    "package.json",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "tsconfig.json",
+    "composer.json",
+    "composer.lock",
+    "Cargo.toml",
+    "Cargo.lock",
+    "pom.xml",
+    "build.gradle",
+    "build.gradle.kts",
+    "settings.gradle",
+    "settings.gradle.kts",
+    "envyr.toml",
+];
+
+// Whether a changed file, relative to the project, is a dependency manifest.
+pub fn is_manifest(relative: &Path) -> bool {
+    MANIFEST_FILES.iter().any(|m| relative == Path::new(m))
+}
+
 pub struct Generator {
     pub pack: Pack,
+    pub no_overwrite: bool,
+    pub force: bool,
+    pub dockerignore_mode: DockerignoreMode,
 }
 
 impl Generator {
     pub fn new(pack: Pack) -> Self {
-        Self { pack }
+        Self {
+            pack,
+            no_overwrite: false,
+            force: false,
+            dockerignore_mode: DockerignoreMode::default(),
+        }
+    }
+
+    pub fn no_overwrite(mut self, no_overwrite: bool) -> Self {
+        self.no_overwrite = no_overwrite;
+        self
+    }
+
+    pub fn dockerignore_mode(mut self, dockerignore_mode: DockerignoreMode) -> Self {
+        self.dockerignore_mode = dockerignore_mode;
+        self
+    }
+
+    // Regenerate even if the inputs are unchanged since the last generation.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    // Hashes the pack along with the manifest mtimes, so unchanged projects can
+    // skip regeneration.
+    fn inputs_hash(&self, project_root: &Path) -> Result<String> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        serde_json::to_string(&self.pack)?.hash(&mut hasher);
+        self.no_overwrite.hash(&mut hasher);
+        self.dockerignore_mode.hash(&mut hasher);
+        templates::dockerfile_template()?.hash(&mut hasher);
+        templates::dockerignore_template()?.hash(&mut hasher);
+        let deps_dir = self.pack.deps_dir(project_root);
+        for dir in [project_root, deps_dir.as_path()] {
+            for manifest in MANIFEST_FILES {
+                let modified = std::fs::metadata(dir.join(manifest))
+                    .and_then(|m| m.modified())
+                    .ok();
+                (manifest, modified).hash(&mut hasher);
+            }
+        }
+        Ok(format!("{:016x}", hasher.finish()))
+    }
+
+    // An existing Dockerfile is kept if asked to, or if it carries the keep marker.
+    fn keep_dockerfile(&self, dockerfile_path: &Path) -> bool {
+        let existing = match std::fs::read_to_string(dockerfile_path) {
+            Ok(existing) => existing,
+            Err(_) => return false,
+        };
+        self.no_overwrite
+            || existing
+                .lines()
+                .next()
+                .is_some_and(|l| l.trim() == KEEP_MARKER)
     }
 
     pub fn generate_meta_dir(&self, project_root: &Path) -> Result<()> {
@@ -27,17 +125,57 @@ impl Generator {
     }
 
     pub fn generate_docker(&self, project_root: &Path) -> Result<()> {
+        let dockerfile_path = project_root.join(".envyr").join("Dockerfile");
+        if self.keep_dockerfile(&dockerfile_path) {
+            debug!(
+                "Preserving existing Dockerfile at {}",
+                dockerfile_path.display()
+            );
+            return Ok(());
+        }
         let dockerfile = docker::generate_dockerfile(&self.pack, project_root)?;
         let dockerignore = docker::generate_docker_ignore(&self.pack)?;
-        let dockerfile_path = project_root.join(".envyr").join("Dockerfile");
-        let dockerignore_path = project_root.join(".dockerignore");
         std::fs::write(dockerfile_path, dockerfile)?;
-        std::fs::write(dockerignore_path, dockerignore)?;
+        self.write_dockerignore(&project_root.join(".dockerignore"), &dockerignore)
+    }
+
+    // A .dockerignore the project already ships is kept, with envyr's entries
+    // merged in, unless asked otherwise.
+    fn write_dockerignore(&self, path: &Path, generated: &str) -> Result<()> {
+        let existing = std::fs::read_to_string(path).ok();
+        let unmanaged = existing
+            .as_deref()
+            .is_some_and(|e| e.lines().any(|l| l.trim() == DOCKERIGNORE_UNMANAGED_MARKER));
+        let contents = match (&self.dockerignore_mode, existing) {
+            (DockerignoreMode::Skip, Some(_)) => return Ok(()),
+            (_, Some(_)) if unmanaged => {
+                debug!("Preserving unmanaged {}", path.display());
+                return Ok(());
+            }
+            (DockerignoreMode::Merge, Some(existing)) => merge_dockerignore(&existing, generated),
+            _ => generated.to_string(),
+        };
+        std::fs::write(path, contents)?;
         Ok(())
     }
 
+    // Renders meta.json and the Dockerfile as `generate` would write them,
+    // without touching the filesystem.
+    pub fn preview(&self, project_root: &Path) -> Result<String> {
+        let dockerfile_path = project_root.join(".envyr").join("Dockerfile");
+        let dockerfile = match self.keep_dockerfile(&dockerfile_path) {
+            true => std::fs::read_to_string(&dockerfile_path)?,
+            false => docker::generate_dockerfile(&self.pack, project_root)?,
+        };
+        Ok(format!(
+            "==> .envyr/meta.json <==\n{}\n\n==> .envyr/Dockerfile <==\n{}",
+            serde_json::to_string_pretty(&self.pack)?,
+            dockerfile.trim_start()
+        ))
+    }
+
     pub fn generate_python(&self, project_root: &Path) -> Result<()> {
-        if !utils::check_requirements_txt(project_root) {
+        if utils::detect_python_installer(&self.pack.deps_dir(project_root)).is_none() {
             // Attempt to generate with pipreqs
             if utils::create_requirements_txt(project_root).is_err() {
                 log::warn!("No requirements.txt found. Unable to generate using pipreqs.");
@@ -47,6 +185,17 @@ impl Generator {
     }
 
     pub fn generate(&self, project_root: &Path) -> Result<()> {
+        let meta_dir = project_root.join(".envyr");
+        let hash_path = meta_dir.join(".hash");
+        let generated = meta_dir.join("meta.json").exists() && meta_dir.join("Dockerfile").exists();
+        if !self.force
+            && generated
+            && std::fs::read_to_string(&hash_path).ok() == Some(self.inputs_hash(project_root)?)
+        {
+            debug!("Project unchanged since the last generation, skipping.");
+            return Ok(());
+        }
+
         self.generate_meta_dir(project_root)?;
         // Write the json file to the meta dir
         self.pack.save(project_root)?;
@@ -58,10 +207,46 @@ impl Generator {
 
         // Generate the dockerfile
         self.generate_docker(project_root)?;
+
+        // Hashed last, to include anything generated above
+        std::fs::write(hash_path, self.inputs_hash(project_root)?)?;
         Ok(())
     }
 }
 
+// Adds the generated entries missing from an existing .dockerignore, keeping
+// everything already in it.
+fn merge_dockerignore(existing: &str, generated: &str) -> String {
+    let present = existing.lines().map(str::trim).collect::<Vec<_>>();
+    let missing = generated
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#') && !present.contains(l))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return existing.to_string();
+    }
+    let mut merged = existing.trim_end().to_string();
+    if !merged.is_empty() {
+        merged.push('\n');
+    }
+    merged.push_str("# Added by envyr\n");
+    for line in missing {
+        merged.push_str(line);
+        merged.push('\n');
+    }
+    merged
+}
+
+// How `generate` treats a .dockerignore already at the project root.
+#[derive(Debug, Default, Clone, Hash, ValueEnum, Serialize, Deserialize)]
+pub enum DockerignoreMode {
+    Overwrite,
+    #[default]
+    Merge,
+    Skip,
+}
+
 #[derive(Debug, Default, Clone, ValueEnum, Serialize, Deserialize)]
 pub enum Executors {
     #[default]
@@ -106,3 +291,185 @@ pub fn store_alias(envyr_root: &Path, name: String, conf: RunConfig) -> Result<(
     std::fs::write(aliases_f, aliases)?;
     Ok(())
 }
+
+// Outcome of a run, recorded per alias or source in runs.json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    // Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub exit_code: i32,
+    pub tag: String,
+}
+
+pub type RunsMap = std::collections::BTreeMap<String, Vec<RunRecord>>;
+
+// Runs kept per name, oldest dropped first.
+const RUNS_KEPT: usize = 20;
+
+pub fn load_runs(envyr_root: &Path) -> Result<RunsMap> {
+    let runs_f = envyr_root.join("runs.json");
+    if !runs_f.exists() {
+        return Ok(RunsMap::new());
+    }
+    let runs = std::fs::read_to_string(runs_f)?;
+    Ok(serde_json::from_str(&runs)?)
+}
+
+pub fn record_run(envyr_root: &Path, name: String, record: RunRecord) -> Result<()> {
+    let mut runs = load_runs(envyr_root)?;
+    let history = runs.entry(name).or_default();
+    history.push(record);
+    if history.len() > RUNS_KEPT {
+        history.drain(..history.len() - RUNS_KEPT);
+    }
+    let runs_f = envyr_root.join("runs.json");
+    let runs = serde_json::to_string_pretty(&runs)?;
+    std::fs::write(runs_f, runs)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envyr::package::PType;
+
+    fn shell_pack() -> Pack {
+        Pack {
+            version: crate::envyr::package::META_VERSION,
+            name: "app".to_string(),
+            interpreter: "/bin/sh".to_string(),
+            ptype: PType::Shell,
+            deps: vec![],
+            manual_deps: vec![],
+            pre_args: vec![],
+            deps_root: None,
+            base_image: None,
+            commands: Default::default(),
+            requirements: vec![],
+            secrets: vec![],
+            entrypoint: "run.sh".into(),
+        }
+    }
+
+    #[test]
+    fn test_generate_keeps_dockerfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let dockerfile_path = dir.path().join(".envyr").join("Dockerfile");
+
+        Generator::new(shell_pack()).generate(dir.path()).unwrap();
+        std::fs::write(&dockerfile_path, "FROM custom\n").unwrap();
+        Generator::new(shell_pack())
+            .no_overwrite(true)
+            .generate(dir.path())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&dockerfile_path).unwrap(),
+            "FROM custom\n"
+        );
+
+        let marked = format!("{}\nFROM custom\n", KEEP_MARKER);
+        std::fs::write(&dockerfile_path, &marked).unwrap();
+        Generator::new(shell_pack()).generate(dir.path()).unwrap();
+        assert_eq!(std::fs::read_to_string(&dockerfile_path).unwrap(), marked);
+        assert!(dir.path().join(".envyr").join("meta.json").exists());
+
+        std::fs::write(&dockerfile_path, "FROM custom\n").unwrap();
+        Generator::new(shell_pack())
+            .force(true)
+            .generate(dir.path())
+            .unwrap();
+        assert_ne!(
+            std::fs::read_to_string(&dockerfile_path).unwrap(),
+            "FROM custom\n"
+        );
+    }
+
+    #[test]
+    fn test_generate_dockerignore_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".dockerignore");
+        let generate = |mode: DockerignoreMode| {
+            Generator::new(shell_pack())
+                .force(true)
+                .dockerignore_mode(mode)
+                .generate(dir.path())
+                .unwrap();
+            std::fs::read_to_string(&path).unwrap()
+        };
+
+        std::fs::write(&path, "dist\n**/.git\n").unwrap();
+        let merged = generate(DockerignoreMode::Merge);
+        assert!(merged.starts_with("dist\n**/.git\n# Added by envyr\n"));
+        assert_eq!(merged.lines().filter(|l| *l == "**/.git").count(), 1);
+        assert_eq!(generate(DockerignoreMode::Merge), merged);
+
+        std::fs::write(&path, "dist\n").unwrap();
+        assert_eq!(generate(DockerignoreMode::Skip), "dist\n");
+        let unmanaged = format!("{}\ndist\n", DOCKERIGNORE_UNMANAGED_MARKER);
+        std::fs::write(&path, &unmanaged).unwrap();
+        assert_eq!(generate(DockerignoreMode::Overwrite), unmanaged);
+
+        std::fs::write(&path, "dist\n").unwrap();
+        assert!(!generate(DockerignoreMode::Overwrite).contains("dist"));
+    }
+
+    #[test]
+    fn test_preview_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let preview = Generator::new(shell_pack()).preview(dir.path()).unwrap();
+        assert!(preview.starts_with("==> .envyr/meta.json <==\n{"));
+        assert!(preview.contains("==> .envyr/Dockerfile <==\n# Envyr Base"));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_generate_skips_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let dockerfile_path = dir.path().join(".envyr").join("Dockerfile");
+        Generator::new(shell_pack()).generate(dir.path()).unwrap();
+
+        // Unchanged inputs leave the generated files alone
+        std::fs::write(&dockerfile_path, "FROM stale\n").unwrap();
+        Generator::new(shell_pack()).generate(dir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&dockerfile_path).unwrap(),
+            "FROM stale\n"
+        );
+
+        // Changed inputs regenerate
+        let mut pack = shell_pack();
+        pack.deps = vec!["curl".to_string()];
+        Generator::new(pack).generate(dir.path()).unwrap();
+        assert!(std::fs::read_to_string(&dockerfile_path)
+            .unwrap()
+            .contains("curl"));
+
+        std::fs::write(dir.path().join("envyr.toml"), "").unwrap();
+        std::fs::write(&dockerfile_path, "FROM stale\n").unwrap();
+        let mut pack = shell_pack();
+        pack.deps = vec!["curl".to_string()];
+        Generator::new(pack).generate(dir.path()).unwrap();
+        assert_ne!(
+            std::fs::read_to_string(&dockerfile_path).unwrap(),
+            "FROM stale\n"
+        );
+    }
+
+    #[test]
+    fn test_record_run() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_runs(dir.path()).unwrap().is_empty());
+        for exit_code in 0..25 {
+            let record = RunRecord {
+                timestamp: 1_700_000_000,
+                exit_code,
+                tag: "latest".to_string(),
+            };
+            record_run(dir.path(), "tool".to_string(), record).unwrap();
+        }
+        let runs = load_runs(dir.path()).unwrap();
+        assert_eq!(runs["tool"].len(), RUNS_KEPT);
+        assert_eq!(runs["tool"][0].exit_code, 5);
+        assert_eq!(runs["tool"].last().unwrap().exit_code, 24);
+    }
+}
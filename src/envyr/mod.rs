@@ -1,7 +1,13 @@
+pub mod config;
+pub mod detect;
 pub mod docker;
+pub mod error;
+pub mod install;
+pub mod k8s;
 pub mod meta;
 pub mod package;
 pub mod templates;
 pub mod utils;
+pub mod watch;
 
 pub mod adapters;
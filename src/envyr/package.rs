@@ -1,39 +1,112 @@
+use super::config::ProjectConfig;
+use super::detect;
+use super::error::EnvyrError;
 use super::utils::{self, PRIORITY_LAST};
 use anyhow::Result;
 use clap::ValueEnum;
 use log::debug;
 use pathdiff::diff_paths;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use walkdir::{DirEntry, WalkDir};
 
 #[derive(Debug, Default, Clone, ValueEnum, Serialize, Deserialize)]
 pub enum PType {
+    #[serde(alias = "python")]
     Python,
+    #[serde(alias = "node")]
     Node,
+    #[serde(alias = "shell")]
     Shell,
+    #[serde(alias = "php")]
+    Php,
+    #[serde(alias = "perl")]
+    Perl,
+    #[serde(alias = "rust")]
+    Rust,
+    #[serde(alias = "java")]
+    Java,
     #[default]
+    #[serde(alias = "other")]
     Other,
 }
 
+// Version of the meta.json layout written by this build, bumped on
+// incompatible changes along with a step in `migrate_meta`.
+pub const META_VERSION: u64 = 1;
+
+// Schema meta.json is validated against on load.
+const META_SCHEMA: &str = include_str!("meta.schema.json");
+
 // Pack is the base struct holding the Package information.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Pack {
+    pub version: u64,
     pub name: String,
     pub interpreter: String,
     pub ptype: PType,
     pub deps: Vec<String>,
+    // Deps added by hand with --dep, kept across regenerations.
+    #[serde(default)]
+    pub manual_deps: Vec<String>,
     pub entrypoint: PathBuf,
+    // Interpreter arguments placed before the entrypoint.
+    #[serde(default)]
+    pub pre_args: Vec<String>,
+    // Directory holding the dependency manifests, relative to the project.
+    #[serde(default)]
+    pub deps_root: Option<PathBuf>,
+    #[serde(default)]
+    pub base_image: Option<String>,
+    // Named alternative entrypoints, selected by the first run arg.
+    #[serde(default)]
+    pub commands: BTreeMap<String, PathBuf>,
+    // Python requirements files, relative to the deps dir. When empty,
+    // requirements.txt and common secondary files are detected.
+    #[serde(default)]
+    pub requirements: Vec<PathBuf>,
+    // Build secret ids mounted into the dependency install steps.
+    #[serde(default)]
+    pub secrets: Vec<String>,
 }
 impl Pack {
-    #[allow(dead_code)]
     pub fn load(project_root: &Path) -> Result<Self> {
         let meta_file = project_root.join(".envyr").join("meta.json");
-        let meta_json = std::fs::read_to_string(meta_file)?;
-        let pack: Pack = serde_json::from_str(&meta_json)?;
+        let meta_json = std::fs::read_to_string(&meta_file)?;
+        let mut meta: serde_json::Value = serde_json::from_str(&meta_json)
+            .map_err(|e| anyhow::anyhow!("{} is not valid JSON: {}", meta_file.display(), e))?;
+        let migrated = migrate_meta(&mut meta)?;
+        validate_meta(&meta).map_err(|e| {
+            anyhow::anyhow!(
+                "Invalid {} (schema version {}): {}",
+                meta_file.display(),
+                META_VERSION,
+                e
+            )
+        })?;
+        let pack: Pack = serde_json::from_value(meta)?;
+        // Loading never writes, the upgrade is saved by the next generation
+        if migrated {
+            debug!(
+                "Upgraded {} to version {} in memory",
+                meta_file.display(),
+                META_VERSION
+            );
+        }
         Ok(pack)
     }
 
+    // The directory dependency manifests are read from.
+    pub fn deps_dir(&self, project_root: &Path) -> PathBuf {
+        match &self.deps_root {
+            Some(deps_root) => project_root.join(deps_root),
+            None => project_root.to_path_buf(),
+        }
+    }
+
     pub fn save(&self, project_root: &Path) -> Result<()> {
         let meta_file = project_root.join(".envyr").join("meta.json");
         let meta_json = serde_json::to_string_pretty(&self)?;
@@ -55,6 +128,16 @@ pub struct PackBuilder {
     entrypoint: Option<PathBuf>,
     executables: Vec<(PathBuf, String, u8)>,
     ptype: PType,
+    manual_deps: Vec<String>,
+    no_auto_deps: bool,
+    pre_args: Vec<String>,
+    deps_root: Option<PathBuf>,
+    base_image: Option<String>,
+    commands: BTreeMap<String, PathBuf>,
+    requirements: Vec<PathBuf>,
+    secrets: Vec<String>,
+    select: bool,
+    detection: Option<detect::Detection>,
 }
 
 impl PackBuilder {
@@ -78,13 +161,191 @@ impl PackBuilder {
         self
     }
 
-    pub fn build(mut self) -> Result<Pack> {
+    pub fn pre_args(mut self, pre_args: Vec<String>) -> Self {
+        self.pre_args = pre_args;
+        self
+    }
+
+    pub fn deps_root(mut self, deps_root: PathBuf) -> Self {
+        self.deps_root = Some(deps_root);
+        self
+    }
+
+    pub fn requirements(mut self, requirements: Vec<PathBuf>) -> Self {
+        self.requirements = requirements;
+        self
+    }
+
+    pub fn secrets(mut self, secrets: Vec<String>) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    // Prompt for the entrypoint when several are detected and stdin is a terminal.
+    pub fn select(mut self, select: bool) -> Self {
+        self.select = select;
+        self
+    }
+
+    pub fn deps(mut self, deps: Vec<String>) -> Self {
+        for dep in deps {
+            if !self.manual_deps.contains(&dep) {
+                self.manual_deps.push(dep);
+            }
+        }
+        self
+    }
+
+    // Skips dependency detection and drops previously saved manual deps, so
+    // only the deps given via `deps` are used.
+    pub fn no_auto_deps(mut self, manual_deps: Vec<String>) -> Self {
+        self.no_auto_deps = true;
+        self.manual_deps = vec![];
+        self.deps(manual_deps)
+    }
+
+    // Offers the scripts README.md shows being run as likely entrypoints,
+    // alongside the detected ones rather than instead of them.
+    pub fn guess_from_readme(mut self) -> Self {
+        for (entrypoint, interpreter) in utils::readme_entrypoints(&self.project_root) {
+            debug!("README runs: {:?} with {:?}", entrypoint, interpreter);
+            if let Some(exe) = self.executables.iter_mut().find(|e| e.0 == entrypoint) {
+                exe.2 = exe.2.min(utils::PRIORITY_LIKELY);
+                continue;
+            }
+            let interpreter = match interpreter.as_deref() {
+                Some("sh") => "/bin/sh".to_string(),
+                Some(interpreter) => format!("/usr/bin/env {}", interpreter),
+                None if entrypoint.extension().is_some_and(|e| e == "sh") => "/bin/sh".to_string(),
+                // Without a shebang or a named interpreter, there is nothing to run it with
+                None => continue,
+            };
+            self.executables
+                .push((entrypoint, interpreter, utils::PRIORITY_LIKELY));
+        }
+        self
+    }
+
+    // Finds the executable an entrypoint refers to, by its path or by a bare
+    // file name such as `deploy` or `deploy.py`.
+    fn match_executable(&self, entrypoint: &Path) -> Result<Option<(PathBuf, String, u8)>> {
+        if let Some(exe) = self.executables.iter().find(|e| e.0 == entrypoint) {
+            return Ok(Some(exe.clone()));
+        }
+        if entrypoint.components().count() != 1 {
+            return Ok(None);
+        }
+        let name = entrypoint.as_os_str();
+        let matches = self
+            .executables
+            .iter()
+            .filter(|e| e.0.file_name() == Some(name) || e.0.file_stem() == Some(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        match matches.len() {
+            0 | 1 => Ok(matches.into_iter().next()),
+            _ => Err(anyhow::anyhow!(
+                "Entrypoint '{}' matches several files, give its path instead:\n{}",
+                entrypoint.display(),
+                format_candidates(&matches)
+            )),
+        }
+    }
+
+    // What detection and the overrides settled on so far, for `envyr inspect`.
+    // Nothing is chosen or checked, unlike in `build`.
+    pub fn inspect(&self) -> Inspection {
+        let mut candidates = self
+            .executables
+            .iter()
+            .map(|(path, interpreter, priority)| Candidate {
+                path: path.clone(),
+                interpreter: interpreter.clone(),
+                priority: *priority,
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|c| c.priority);
+        let mut deps = match self.no_auto_deps {
+            true => vec![],
+            false => detect_source_deps(&self.project_root, &self.ptype),
+        };
+        for dep in self.manual_deps.iter() {
+            if !deps.contains(dep) {
+                deps.push(dep.clone());
+            }
+        }
+        deps.sort();
+        Inspection {
+            project_root: self.project_root.clone(),
+            name: self.name.clone(),
+            ptype: self.ptype.clone(),
+            detection: self.detection.clone(),
+            interpreter: self
+                .interpreter
+                .clone()
+                .or_else(|| detect::default_interpreter(&self.ptype)),
+            entrypoint: self.entrypoint.clone(),
+            candidates,
+            deps,
+            deps_root: self
+                .deps_root
+                .clone()
+                .or_else(|| utils::find_deps_root(&self.project_root, &self.ptype)),
+            commands: self.commands.clone(),
+        }
+    }
+
+    pub fn build(mut self) -> Result<Pack, EnvyrError> {
         // Check values
         if self.name.is_none() {
-            return Err(anyhow::anyhow!(
-                "Could not detect project name. Please specify it manually."
+            return Err(EnvyrError::DetectionFailed(
+                "Could not detect project name. Please specify it manually.".to_string(),
             ));
         }
+        self.entrypoint = self
+            .entrypoint
+            .take()
+            .map(|e| normalize_entrypoint(&self.project_root, &e));
+        if matches!(self.ptype, PType::Rust) {
+            // Cargo projects run the binary they build, never a script
+            self.entrypoint = match self.entrypoint.take() {
+                Some(bin) if bin.components().count() == 1 => {
+                    Some(utils::rust_binary_path(&bin.to_string_lossy()))
+                }
+                Some(entrypoint) => Some(entrypoint),
+                None => deduce_entrypoint(PType::Rust, &self.project_root),
+            };
+            if self.entrypoint.is_none() {
+                return Err(EnvyrError::DetectionFailed(
+                    "Could not detect the binary to build, e.g. in a workspace. Select one with --entrypoint.".to_string(),
+                ));
+            }
+        }
+        if matches!(self.ptype, PType::Java) {
+            // JVM projects run the jar they build, a bare name picks one of several
+            let build = utils::detect_java_build(&self.project_root).unwrap_or_default();
+            self.entrypoint = match self.entrypoint.take() {
+                Some(jar) if jar.components().count() == 1 => {
+                    Some(utils::java_jar_path(&build, &jar.to_string_lossy()))
+                }
+                Some(entrypoint) => Some(entrypoint),
+                None => deduce_entrypoint(PType::Java, &self.project_root),
+            };
+            if self.entrypoint.is_none() {
+                return Err(EnvyrError::DetectionFailed(
+                    "Could not detect the jar the build produces, e.g. with a multi-module build or a version property. Select it with --entrypoint, e.g. --entrypoint target/app-1.0.jar.".to_string(),
+                ));
+            }
+        }
+        if let Some(entrypoint) = self.entrypoint.clone() {
+            // A discovered executable also brings its interpreter along
+            if let Some((path, interpreter, _)) = self.match_executable(&entrypoint)? {
+                self.entrypoint = Some(path);
+                if self.interpreter.is_none() {
+                    self.interpreter = Some(interpreter);
+                }
+            }
+        }
         if self.entrypoint.is_none() {
             if self.executables.is_empty() {
                 // Try to deduce based on project type
@@ -93,25 +354,34 @@ impl PackBuilder {
                     debug!("Deduced entrypoint based on project type: {:?}", entrypoint);
                     self.entrypoint = Some(entrypoint);
                 } else {
-                    return Err(anyhow::anyhow!(
+                    return Err(EnvyrError::DetectionFailed(
                         "Could not detect project entrypoint. Please specify it manually."
+                            .to_string(),
                     ));
                 }
             } else if self.executables.len() > 1 {
                 debug!("Multiple executables found, trying lowest priority one.");
                 // Get the lowest priority one
-                self.executables.sort_by(|a, b| a.2.cmp(&b.2));
-                // If multiple files with lowest priority are found then error out.
-                if self.executables[0].2 == self.executables[1].2 {
-                    return Err(anyhow::anyhow!(
-                        "Multiple entrypoints detected! {:?}. Please choose one manually.",
-                        self.executables
-                    ));
-                } else {
-                    // Otherwise use the lowest priority one.
-                    self.entrypoint = Some(self.executables[0].0.clone());
-                    self.interpreter = Some(self.executables[0].1.clone());
-                }
+                self.executables.sort_by_key(|e| e.2);
+                let tied = self
+                    .executables
+                    .iter()
+                    .filter(|e| e.2 == self.executables[0].2)
+                    .count();
+                let chosen = match tied {
+                    1 => 0,
+                    _ if self.select && std::io::stdin().is_terminal() => {
+                        prompt_entrypoint(&self.executables)?
+                    }
+                    _ => {
+                        return Err(EnvyrError::DetectionFailed(format!(
+                            "Multiple entrypoints detected, choose one with --entrypoint (or --select):\n{}",
+                            format_candidates(&self.executables[..tied])
+                        )));
+                    }
+                };
+                self.entrypoint = Some(self.executables[chosen].0.clone());
+                self.interpreter = Some(self.executables[chosen].1.clone());
             } else {
                 // Only one executable found.
                 self.entrypoint = Some(self.executables[0].0.clone());
@@ -120,44 +390,337 @@ impl PackBuilder {
             debug!("Deduced entrypoint: {:?}", self.entrypoint);
             debug!("Deduced interpreter: {:?}", self.interpreter);
         }
+        // A bad override or manifest fails here rather than in the image build.
+        // Rust and Java entrypoints are only built later.
+        let built = matches!(self.ptype, PType::Rust | PType::Java);
+        if let (Some(entrypoint), false) = (&self.entrypoint, built) {
+            let exists = match utils::python_module(entrypoint) {
+                Some(module) => utils::python_module_exists(&self.project_root, module),
+                None if utils::is_npm_start(entrypoint) => {
+                    utils::detect_node_start(&self.project_root)
+                }
+                None => self.project_root.join(entrypoint).is_file(),
+            };
+            if !exists {
+                let mut message = format!(
+                    "Entrypoint {} does not exist in the project.",
+                    entrypoint.display()
+                );
+                if !self.executables.is_empty() {
+                    message.push_str(&format!(
+                        " Detected candidates:\n{}",
+                        format_candidates(&self.executables)
+                    ));
+                }
+                return Err(EnvyrError::DetectionFailed(message));
+            }
+        }
+        // Only python runs modules
+        let module = self.entrypoint.as_deref().and_then(utils::python_module);
+        if module.is_some() && matches!(self.ptype, PType::Other) {
+            self.ptype = PType::Python;
+        }
+        // npm runs the start script, whatever interpreter it uses
+        if self.entrypoint.as_deref().is_some_and(utils::is_npm_start) {
+            if matches!(self.ptype, PType::Other) {
+                self.ptype = PType::Node;
+            }
+            self.interpreter.get_or_insert("npm".to_string());
+        }
+        // A shell script's own shebang beats the /bin/sh default, e.g. for
+        // one needing bash that wasn't picked from the candidates
+        if self.interpreter.is_none() && matches!(self.ptype, PType::Shell) {
+            if let Some(entrypoint) = &self.entrypoint {
+                self.interpreter = utils::check_shebang_file(&self.project_root.join(entrypoint))
+                    .unwrap_or(None)
+                    .map(|interpreter| interpreter.trim().to_string());
+            }
+        }
+        // Scripts without a known extension are typed by their shebang
+        if matches!(self.ptype, PType::Other) {
+            if let Some(ptype) = self
+                .interpreter
+                .as_deref()
+                .and_then(utils::map_interpreter_to_ptype)
+            {
+                self.ptype = ptype;
+            }
+        }
         if self.interpreter.is_none() {
             // Attempt to deduce from PType.
-            if let Some(interpreter) = deduce_interpreter(self.ptype.clone()) {
+            if let Some(interpreter) = detect::default_interpreter(&self.ptype) {
                 debug!(
                     "Deduced interpreter based on project type: {:?}",
                     interpreter
                 );
                 self.interpreter = Some(interpreter);
             } else {
-                return Err(anyhow::anyhow!(
-                    "Could not detect project interpreter. Please specify it manually."
+                return Err(EnvyrError::DetectionFailed(
+                    "Could not detect project interpreter. Please specify it manually.".to_string(),
                 ));
             }
         }
 
+        // Manifests may live further up in a monorepo
+        let deps_root = self
+            .deps_root
+            .or_else(|| utils::find_deps_root(&self.project_root, &self.ptype));
+        if deps_root.is_some() {
+            debug!("Using dependency manifests from: {:?}", deps_root);
+        }
+
+        let deps_dir = self
+            .project_root
+            .join(deps_root.clone().unwrap_or_default());
+        if let Some(missing) = self
+            .requirements
+            .iter()
+            .find(|r| !deps_dir.join(r).is_file())
+        {
+            return Err(EnvyrError::DetectionFailed(format!(
+                "Requirements file not found: {}",
+                deps_dir.join(missing).display()
+            )));
+        }
+
         let mut deps = vec![];
 
-        if let Some(interp) = self.interpreter.clone() {
-            if let Some(entryp) = self.entrypoint.clone() {
-                debug!("Checking for available os-level dependencies");
-                if interp.contains("bash") {
-                    deps = utils::check_bash_dependencies(&self.project_root.clone().join(entryp))
-                        .unwrap_or_default();
-                    debug!("Found deps after analysis: {:?}", deps);
+        if !self.no_auto_deps {
+            if let Some(interp) = self.interpreter.clone() {
+                if let Some(entryp) = self.entrypoint.clone() {
+                    debug!("Checking for available os-level dependencies");
+                    if interp.contains("bash") {
+                        deps =
+                            utils::check_bash_dependencies(&self.project_root.clone().join(entryp))
+                                .unwrap_or_default();
+                        debug!("Found deps after analysis: {:?}", deps);
+                    }
+                }
+            }
+            for dep in detect_source_deps(&self.project_root, &self.ptype) {
+                if !deps.contains(&dep) {
+                    deps.push(dep);
                 }
             }
         }
 
+        for dep in self.manual_deps.iter() {
+            if !deps.contains(dep) {
+                deps.push(dep.clone());
+            }
+        }
+        // Sorted so meta.json is the same wherever it is generated. Order
+        // matters for pre_args and requirements, which are kept as given.
+        deps.sort();
+        let mut manual_deps = self.manual_deps;
+        manual_deps.sort();
+        manual_deps.dedup();
+
         Ok(Pack {
+            version: META_VERSION,
             name: self.name.unwrap_or_default(),
             interpreter: self.interpreter.unwrap_or_default(),
             entrypoint: self.entrypoint.unwrap_or_default(),
             ptype: self.ptype,
             deps,
+            manual_deps,
+            pre_args: self.pre_args,
+            deps_root,
+            base_image: self.base_image,
+            commands: self.commands,
+            requirements: self.requirements,
+            secrets: self.secrets,
         })
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub project_root: PathBuf,
+    pub name: Option<String>,
+    pub ptype: PType,
+    pub detection: Option<detect::Detection>,
+    // Given, or the default for the type. Candidates bring their own.
+    pub interpreter: Option<String>,
+    // Only set when given, otherwise picked from the candidates.
+    pub entrypoint: Option<PathBuf>,
+    // Possible entrypoints, the lowest priority is preferred.
+    pub candidates: Vec<Candidate>,
+    pub deps: Vec<String>,
+    pub deps_root: Option<PathBuf>,
+    pub commands: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub interpreter: String,
+    pub priority: u8,
+}
+
+// Makes an entrypoint override relative to the project root. It may be given
+// as ./main.py, as an absolute path, or relative to the repository when the
+// project is a --sub-dir of it, e.g. cli/main.py for the project cli.
+fn normalize_entrypoint(project_root: &Path, entrypoint: &Path) -> PathBuf {
+    let entrypoint = entrypoint
+        .strip_prefix(project_root)
+        .unwrap_or(entrypoint)
+        .components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .collect::<PathBuf>();
+    if project_root.join(&entrypoint).exists() {
+        return entrypoint;
+    }
+    let components = entrypoint.components().collect::<Vec<_>>();
+    for n in 1..components.len() {
+        let prefix = components[..n].iter().collect::<PathBuf>();
+        let rest = components[n..].iter().collect::<PathBuf>();
+        if project_root.ends_with(&prefix) && project_root.join(&rest).exists() {
+            return rest;
+        }
+    }
+    entrypoint
+}
+
+fn format_candidates(executables: &[(PathBuf, String, u8)]) -> String {
+    executables
+        .iter()
+        .map(|e| format!("  {}", e.0.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Lists the executables on stderr and reads the chosen number from stdin.
+fn prompt_entrypoint(executables: &[(PathBuf, String, u8)]) -> Result<usize> {
+    eprintln!("Multiple entrypoints detected:");
+    for (i, e) in executables.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, e.0.display());
+    }
+    eprint!("Run which one? [1-{}]: ", executables.len());
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().parse::<usize>() {
+        Ok(n) if (1..=executables.len()).contains(&n) => Ok(n - 1),
+        _ => Err(anyhow::anyhow!(
+            "'{}' is not one of the choices.",
+            answer.trim()
+        )),
+    }
+}
+
+// Upgrades an older meta.json in place, returning whether anything changed.
+fn migrate_meta(meta: &mut serde_json::Value) -> Result<bool> {
+    let Some(fields) = meta.as_object_mut() else {
+        return Ok(false);
+    };
+    // Files written before versioning have no version field.
+    let version = match fields.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("`version` must be a non-negative integer."))?,
+    };
+    if version > META_VERSION {
+        return Err(anyhow::anyhow!(
+            "meta.json is version {}, this envyr only understands up to version {}. Upgrade envyr or regenerate with --autogen.",
+            version,
+            META_VERSION
+        ));
+    }
+    if version == META_VERSION {
+        return Ok(false);
+    }
+    // 0 -> 1: the version field was added, everything else is compatible.
+    fields.insert("version".to_string(), META_VERSION.into());
+    Ok(true)
+}
+
+// Checks meta.json against the embedded schema. Only the keywords used by
+// the schema are supported: type, required, enum, properties and items.
+fn validate_meta(meta: &serde_json::Value) -> Result<()> {
+    let schema: serde_json::Value = serde_json::from_str(META_SCHEMA)?;
+    validate_value(meta, &schema, "")
+}
+
+fn validate_value(value: &serde_json::Value, schema: &serde_json::Value, path: &str) -> Result<()> {
+    let field = match path {
+        "" => "meta.json".to_string(),
+        _ => format!("`{}`", path),
+    };
+    if let Some(types) = schema.get("type") {
+        let types = match types {
+            serde_json::Value::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+            t => vec![t.as_str().unwrap_or_default()],
+        };
+        if !types.iter().any(|t| json_type_matches(value, t)) {
+            return Err(anyhow::anyhow!(
+                "{} must be {}, found {}.",
+                field,
+                types.join(" or "),
+                value
+            ));
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(|e| e.as_array()) {
+        if !allowed.contains(value) {
+            let allowed = allowed
+                .iter()
+                .filter_map(|v| v.as_str())
+                .collect::<Vec<_>>();
+            return Err(anyhow::anyhow!(
+                "{} must be one of {}, found {}.",
+                field,
+                allowed.join(", "),
+                value
+            ));
+        }
+    }
+    if let Some(fields) = value.as_object() {
+        for required in schema
+            .get("required")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r.as_str())
+        {
+            if !fields.contains_key(required) {
+                return Err(anyhow::anyhow!("missing required field `{}`.", required));
+            }
+        }
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        for (name, property) in properties.into_iter().flatten() {
+            if let Some(v) = fields.get(name) {
+                validate_value(v, property, name)?;
+            }
+        }
+        if let Some(additional) = schema.get("additionalProperties") {
+            for (name, v) in fields {
+                if !properties.is_some_and(|p| p.contains_key(name)) {
+                    validate_value(v, additional, &format!("{}.{}", path, name))?;
+                }
+            }
+        }
+    }
+    if let (Some(items), Some(schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate_value(item, schema, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
+
+fn json_type_matches(value: &serde_json::Value, json_type: &str) -> bool {
+    match json_type {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_u64() || value.is_i64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
 fn detect_name(project_root: &Path) -> Option<String> {
     let name = project_root.file_name()?.to_str()?;
     Some(name.to_string())
@@ -179,34 +742,52 @@ fn ignore_dir(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-fn deduce_entrypoint(ptype: PType, project_root: &Path) -> Option<PathBuf> {
-    match ptype {
-        PType::Node => utils::detect_main_node(project_root),
-        _ => None,
+type DepScanner = fn(&str) -> Vec<String>;
+
+// Scans the project sources for commands shelled out to, for the ptypes that
+// support it.
+fn detect_source_deps(project_root: &Path, ptype: &PType) -> Vec<String> {
+    let (extensions, scan): (&[&str], DepScanner) = match ptype {
+        PType::Python => (&["py"], utils::check_python_os_deps),
+        PType::Node => (&["js", "ts"], utils::check_node_os_deps),
+        _ => return vec![],
+    };
+    let mut deps = vec![];
+    for entry in WalkDir::new(project_root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !(is_hidden(e) || ignore_dir(e)))
+        .flatten()
+    {
+        let ext = entry.path().extension().unwrap_or_default();
+        if !entry.file_type().is_file() || !extensions.iter().any(|e| ext == *e) {
+            continue;
+        }
+        let Ok(code) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        for dep in scan(&code) {
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
     }
+    debug!("Found deps in sources: {:?}", deps);
+    deps
 }
 
-fn deduce_interpreter(ptype: PType) -> Option<String> {
+fn deduce_entrypoint(ptype: PType, project_root: &Path) -> Option<PathBuf> {
     match ptype {
-        PType::Python => Some("/usr/bin/env python".to_string()),
-        PType::Node => Some("/usr/bin/env node".to_string()),
-        PType::Shell => Some("/bin/sh".to_string()),
+        // A file named by main is run directly, else the start script
+        PType::Node => utils::detect_main_node(project_root).or_else(|| {
+            utils::detect_node_start(project_root).then(|| PathBuf::from(utils::NPM_START))
+        }),
+        PType::Rust => utils::detect_rust_binary(project_root).map(|b| utils::rust_binary_path(&b)),
+        PType::Java => utils::detect_java_jar(project_root),
         _ => None,
     }
 }
 
-fn detect_ptype(project_root: &Path) -> Option<PType> {
-    // Check package.json
-    if utils::check_package_json(project_root) {
-        return Some(PType::Node);
-    }
-    // Check requirements.txt
-    if utils::check_requirements_txt(project_root) {
-        return Some(PType::Python);
-    }
-    None
-}
-
 fn analyse_project(project_root: &PathBuf) -> Result<PackBuilder> {
     let mut builder = PackBuilder {
         name: detect_name(project_root),
@@ -214,36 +795,22 @@ fn analyse_project(project_root: &PathBuf) -> Result<PackBuilder> {
         ..Default::default()
     };
 
-    // See if the project type can be ascertained
-    if let Some(ptype) = detect_ptype(project_root) {
-        builder.ptype = ptype;
+    // Carry over manual deps from a previous generation
+    if let Ok(previous) = Pack::load(project_root) {
+        builder.manual_deps = previous.manual_deps;
     }
 
-    // Walk the project directory
+    // Walk the project directory, sorted so the results are reproducible
+    let mut files = vec![];
     for entry in WalkDir::new(project_root)
+        .sort_by_file_name()
         .into_iter()
         .filter_entry(|e| !(is_hidden(e) || ignore_dir(e)))
     {
         match entry {
             Ok(entry) => {
                 if entry.file_type().is_file() {
-                    // Do a series of checks
-                    // 1. Check a possible entrypoint
-                    if let Some((f, interpreter, priority)) = detect_possible_entrypoint(&entry) {
-                        let relative_path = diff_paths(&f, project_root).expect(
-                            "Path Diff Error, this should not happen while walking the dir.",
-                        );
-                        builder
-                            .executables
-                            .push((relative_path, interpreter, priority));
-                    }
-                    // 2. Check the file extensions and update ptype if necessary
-                    // Only do this if the ptype isn't already detected via other methods.
-                    if matches!(builder.ptype, PType::Other) {
-                        if let Some(ptype) = detect_ptype_from_extension(&entry) {
-                            builder.ptype = ptype;
-                        }
-                    }
+                    files.push(entry);
                 }
             }
             Err(e) => {
@@ -252,15 +819,62 @@ fn analyse_project(project_root: &PathBuf) -> Result<PackBuilder> {
         }
     }
 
+    // 1. Check possible entrypoints. This reads the files, so is done in parallel.
+    // Collecting keeps the walk order.
+    builder.executables = files
+        .par_iter()
+        .filter_map(detect_possible_entrypoint)
+        .map(|(f, interpreter, priority)| {
+            let relative_path = diff_paths(&f, project_root)
+                .expect("Path Diff Error, this should not happen while walking the dir.");
+            // A package's __main__.py is offered as `-m package`, likely meant to be run
+            match utils::python_main_module(&relative_path) {
+                Some(module) => (
+                    PathBuf::from(format!("-m {}", module)),
+                    interpreter,
+                    priority.min(utils::PRIORITY_LIKELY),
+                ),
+                None => (relative_path, interpreter, priority),
+            }
+        })
+        .collect();
+
+    // 2. See if the project type can be ascertained, from manifests or sources
+    let relative_files = files
+        .iter()
+        .filter_map(|entry| entry.path().strip_prefix(project_root).ok())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    if let Some(detection) = detect::detect(project_root, &relative_files) {
+        debug!(
+            "Detected {:?} (confidence {}) from {:?}",
+            detection.ptype, detection.confidence, detection.marker_files
+        );
+        builder.ptype = detection.ptype.clone();
+        builder.detection = Some(detection);
+    }
+
+    // Settings from envyr.toml take precedence over detection
+    let config = ProjectConfig::load(project_root)?;
+    if config.name.is_some() {
+        builder.name = config.name;
+    }
+    if config.interpreter.is_some() {
+        builder.interpreter = config.interpreter;
+    }
+    if config.entrypoint.is_some() {
+        builder.entrypoint = config.entrypoint;
+    }
+    if let Some(ptype) = config.ptype {
+        builder.ptype = ptype;
+    }
+    builder.base_image = config.base_image;
+    builder.commands = config.commands;
+
     debug!("Project analysis result: {:?}", builder);
     Ok(builder)
 }
 
-fn detect_ptype_from_extension(entry: &DirEntry) -> Option<PType> {
-    let extension = entry.path().extension()?.to_str()?;
-    utils::map_extension_to_ptype(extension)
-}
-
 fn detect_possible_entrypoint(entry: &DirEntry) -> Option<(PathBuf, String, u8)> {
     // Get the extension. If this fails, just use defaults, the shebang checks will run instead
     let extension = entry
@@ -270,14 +884,49 @@ fn detect_possible_entrypoint(entry: &DirEntry) -> Option<(PathBuf, String, u8)>
         .to_str()
         .unwrap_or_default();
 
+    // A shebang pins the interpreter more precisely than the extension can,
+    // e.g. `python3.11`, unless it points into a host virtualenv.
+    let shebang = utils::check_shebang_file(&entry.path().to_path_buf())
+        .unwrap_or(None)
+        .map(|interpreter| interpreter.trim().to_string());
+    let pinned = shebang
+        .clone()
+        .filter(|interpreter| !utils::is_venv_interpreter(interpreter));
+
     match extension {
         // A python file is a possible entrypoint. One with __main__ gets highest priority.
         "py" => {
             let priority = utils::check_python_exec_priority(&entry.path().to_path_buf())
                 .unwrap_or(PRIORITY_LAST);
+            // The python images don't install to /usr/bin, so only the name is kept.
+            let interpreter = pinned
+                .map(
+                    |interpreter| match interpreter.strip_prefix("/usr/bin/env ") {
+                        Some(_) => interpreter,
+                        None => format!(
+                            "/usr/bin/env {}",
+                            interpreter
+                                .rsplit_once('/')
+                                .map_or(interpreter.as_str(), |(_, p)| p)
+                        ),
+                    },
+                )
+                .unwrap_or("/usr/bin/env python".to_string());
+            return Some((entry.path().to_path_buf(), interpreter, priority));
+        }
+        // Any php/perl file may be a CLI script, ones with a shebang more likely so.
+        "php" | "pl" => {
+            let priority = match shebang {
+                Some(_) => utils::PRIORITY_LIKELY,
+                None => PRIORITY_LAST,
+            };
+            let interpreter = match extension {
+                "php" => "/usr/bin/env php",
+                _ => "/usr/bin/env perl",
+            };
             return Some((
                 entry.path().to_path_buf(),
-                "/usr/bin/env python".to_string(),
+                pinned.unwrap_or(interpreter.to_string()),
                 priority,
             ));
         }
@@ -286,16 +935,530 @@ fn detect_possible_entrypoint(entry: &DirEntry) -> Option<(PathBuf, String, u8)>
         _ => {}
     };
 
-    if let Some(interpreter) =
-        utils::check_shebang_file(&entry.path().to_path_buf()).unwrap_or(None)
-    {
-        let interp_trimmed = interpreter.trim();
-        return Some((
+    shebang.map(|interpreter| {
+        (
             entry.path().to_path_buf(),
-            interp_trimmed.to_string(),
+            interpreter,
             utils::PRIORITY_LIKELY,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyse_project_is_ordered() {
+        // Hidden directories are skipped, so avoid the default .tmp prefix
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        for name in ["c.sh", "a.sh", "b.sh"] {
+            std::fs::write(dir.path().join(name), "#!/bin/sh\n").unwrap();
+        }
+        let builder = analyse_project(&dir.path().to_path_buf()).unwrap();
+        let executables = builder
+            .executables
+            .iter()
+            .map(|e| e.0.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(executables, ["a.sh", "b.sh", "c.sh"]);
+        assert!(matches!(builder.ptype, PType::Shell));
+    }
+
+    #[test]
+    fn test_perl_shebang_without_extension() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(dir.path().join("report"), "#!/usr/bin/perl\nprint 1;\n").unwrap();
+        let pack = analyse_project(&dir.path().to_path_buf())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(matches!(pack.ptype, PType::Perl));
+        assert_eq!(pack.interpreter, "/usr/bin/perl");
+    }
+
+    #[test]
+    fn test_python_shebang_pins_interpreter() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("deploy.py"),
+            "#!/usr/bin/env python3.11\nif __name__ == \"__main__\":\n    pass\n",
+        )
+        .unwrap();
+        let pack = analyse_project(&dir.path().to_path_buf())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(matches!(pack.ptype, PType::Python));
+        assert_eq!(pack.interpreter, "/usr/bin/env python3.11");
+    }
+
+    #[test]
+    fn test_python_shebang_paths() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("deploy.py"),
+            "#!/home/me/deploy/.venv/bin/python\nprint(1)\n",
+        )
+        .unwrap();
+        let pack = analyse_project(&dir.path().to_path_buf())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(pack.interpreter, "/usr/bin/env python");
+
+        std::fs::write(
+            dir.path().join("deploy.py"),
+            "#!/usr/bin/python3.12\nprint(1)\n",
+        )
+        .unwrap();
+        let pack = analyse_project(&dir.path().to_path_buf())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(pack.interpreter, "/usr/bin/env python3.12");
+    }
+
+    #[test]
+    fn test_load_migrates_and_validates() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta_dir = dir.path().join(".envyr");
+        std::fs::create_dir(&meta_dir).unwrap();
+        let meta_file = meta_dir.join("meta.json");
+
+        // Files from before versioning are upgraded, on disk only once saved
+        let legacy = r#"{"name": "app", "interpreter": "/bin/sh", "ptype": "Shell", "deps": [], "entrypoint": "run.sh"}"#;
+        std::fs::write(&meta_file, legacy).unwrap();
+        let pack = Pack::load(dir.path()).unwrap();
+        assert_eq!(pack.version, META_VERSION);
+        assert_eq!(std::fs::read_to_string(&meta_file).unwrap(), legacy);
+        pack.save(dir.path()).unwrap();
+        assert!(std::fs::read_to_string(&meta_file)
+            .unwrap()
+            .contains("\"version\": 1"));
+
+        std::fs::write(
+            &meta_file,
+            r#"{"version": 1, "name": "app", "ptype": "Shell", "deps": [], "entrypoint": "run.sh"}"#,
+        )
+        .unwrap();
+        let err = Pack::load(dir.path()).unwrap_err().to_string();
+        assert!(
+            err.contains("missing required field `interpreter`"),
+            "{}",
+            err
+        );
+
+        std::fs::write(
+            &meta_file,
+            r#"{"version": 1, "name": "app", "interpreter": "/bin/sh", "ptype": "Shell", "deps": ["curl", 1], "entrypoint": "run.sh"}"#,
+        )
+        .unwrap();
+        let err = Pack::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("`deps[1]` must be string, found 1"), "{}", err);
+
+        std::fs::write(
+            &meta_file,
+            r#"{"version": 9, "name": "app", "interpreter": "/bin/sh", "ptype": "Shell", "deps": [], "entrypoint": "run.sh"}"#,
+        )
+        .unwrap();
+        let err = Pack::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("version 9"), "{}", err);
+    }
+
+    #[test]
+    fn test_entrypoint_selection() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("bin")).unwrap();
+        for name in ["bin/deploy", "bin/report"] {
+            std::fs::write(dir.path().join(name), "#!/bin/bash\necho\n").unwrap();
+        }
+        let root = dir.path().to_path_buf();
+
+        let err = analyse_project(&root).unwrap().build().unwrap_err();
+        assert!(
+            err.to_string().ends_with("  bin/deploy\n  bin/report"),
+            "{}",
+            err
+        );
+
+        let pack = analyse_project(&root)
+            .unwrap()
+            .entrypoint("report".into())
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("bin/report"));
+        assert_eq!(pack.interpreter, "/bin/bash");
+    }
+
+    #[test]
+    fn test_inspect() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(dir.path().join("requirements.txt"), "requests\n").unwrap();
+        std::fs::write(dir.path().join("helper.py"), "x = 1\n").unwrap();
+        std::fs::write(
+            dir.path().join("main.py"),
+            "if __name__ == \"__main__\":\n    pass\n",
+        )
+        .unwrap();
+        let root = dir.path().to_path_buf();
+
+        let inspection = analyse_project(&root)
+            .unwrap()
+            .deps(vec!["git".to_string()])
+            .inspect();
+        assert!(matches!(inspection.ptype, PType::Python));
+        assert_eq!(
+            inspection.detection.unwrap().marker_files,
+            [PathBuf::from("requirements.txt")]
+        );
+        assert_eq!(inspection.candidates[0].path, PathBuf::from("main.py"));
+        assert_eq!(inspection.candidates[0].priority, utils::PRIORITY_TOP);
+        assert_eq!(inspection.candidates.len(), 2);
+        assert_eq!(inspection.deps, ["git"]);
+        assert_eq!(inspection.entrypoint, None);
+        // Nothing is written
+        assert!(!root.join(".envyr").exists());
+    }
+
+    #[test]
+    fn test_inspect_keeps_legacy_meta() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(dir.path().join("run.sh"), "echo hi\n").unwrap();
+        std::fs::create_dir(dir.path().join(".envyr")).unwrap();
+        let meta_file = dir.path().join(".envyr").join("meta.json");
+        let legacy = r#"{"name": "app", "interpreter": "/bin/sh", "ptype": "Shell", "deps": [], "manual_deps": ["curl"], "entrypoint": "run.sh"}"#;
+        std::fs::write(&meta_file, legacy).unwrap();
+
+        let inspection = analyse_project(&dir.path().to_path_buf())
+            .unwrap()
+            .inspect();
+        assert!(inspection.deps.contains(&"curl".to_string()));
+        assert_eq!(std::fs::read_to_string(&meta_file).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_guess_from_readme() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        for name in ["fetch.py", "report.py", "setup.sh"] {
+            std::fs::write(dir.path().join(name), "x = 1\n").unwrap();
+        }
+        std::fs::write(
+            dir.path().join("README.md"),
+            "Run `python other.py`.\n```\n$ python3 report.py --all\n./setup.sh\npython missing.py\n```\n",
+        )
+        .unwrap();
+        let root = dir.path().to_path_buf();
+
+        // Without the flag, neither file stands out
+        assert!(analyse_project(&root).unwrap().build().is_err());
+
+        let builder = analyse_project(&root).unwrap().guess_from_readme();
+        let candidates = builder.inspect().candidates;
+        assert_eq!(candidates[0].path, PathBuf::from("report.py"));
+        assert_eq!(candidates[0].priority, utils::PRIORITY_LIKELY);
+        assert_eq!(candidates[1].path, PathBuf::from("setup.sh"));
+        assert_eq!(candidates[1].interpreter, "/bin/sh");
+        assert_eq!(candidates.len(), 3);
+
+        // Ties between README scripts still need a choice
+        assert!(builder.build().is_err());
+        std::fs::remove_file(root.join("setup.sh")).unwrap();
+        let pack = analyse_project(&root)
+            .unwrap()
+            .guess_from_readme()
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("report.py"));
+    }
+
+    #[test]
+    fn test_shell_interpreter_from_shebang() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("deploy.sh"),
+            "#!/usr/bin/env bash\nset -euo pipefail\n[[ -n \"$1\" ]] && echo \"${1^^}\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("plain.sh"), "echo hi\n").unwrap();
+        let root = dir.path().to_path_buf();
+        // As when the entrypoint is given without being a detected candidate
+        let builder = |entrypoint: &str| PackBuilder {
+            project_root: root.clone(),
+            name: Some("tool".to_string()),
+            ptype: PType::Shell,
+            entrypoint: Some(entrypoint.into()),
+            ..Default::default()
+        };
+
+        let pack = builder("deploy.sh").build().unwrap();
+        assert_eq!(pack.interpreter, "/usr/bin/env bash");
+        let pack = builder("plain.sh").build().unwrap();
+        assert_eq!(pack.interpreter, "/bin/sh");
+    }
+
+    #[test]
+    fn test_entrypoint_in_sub_dir() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        let cli = dir.path().join("apps").join("cli");
+        std::fs::create_dir_all(&cli).unwrap();
+        std::fs::write(cli.join("main.py"), "print(1)\n").unwrap();
+        std::fs::write(cli.join("admin.py"), "print(2)\n").unwrap();
+
+        for entrypoint in ["main.py", "./main.py", "apps/cli/main.py", "cli/main.py"] {
+            let pack = analyse_project(&cli)
+                .unwrap()
+                .entrypoint(entrypoint.into())
+                .build()
+                .unwrap();
+            assert_eq!(pack.entrypoint, PathBuf::from("main.py"), "{}", entrypoint);
+        }
+        let pack = analyse_project(&cli)
+            .unwrap()
+            .entrypoint(cli.join("admin.py"))
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("admin.py"));
+    }
+
+    #[test]
+    fn test_meta_is_reproducible() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("main.py"),
+            "import subprocess\nsubprocess.run(['git', 'status'])\nsubprocess.run(['curl', 'x'])\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("a.py"),
+            "import os\nos.system('ffmpeg -i x')\n",
+        )
+        .unwrap();
+        let root = dir.path().to_path_buf();
+        let analyse = || {
+            let pack = analyse_project(&root)
+                .unwrap()
+                .entrypoint("main.py".into())
+                .deps(vec![
+                    "zip".to_string(),
+                    "bash".to_string(),
+                    "zip".to_string(),
+                ])
+                .build()
+                .unwrap();
+            serde_json::to_string_pretty(&pack).unwrap()
+        };
+        let meta = analyse();
+        assert_eq!(meta, analyse());
+        let pack: Pack = serde_json::from_str(&meta).unwrap();
+        let mut sorted = pack.deps.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(pack.deps, sorted);
+        assert!(pack.deps.len() > 2, "{:?}", pack.deps);
+        assert_eq!(pack.manual_deps, ["bash", "zip"]);
+    }
+
+    #[test]
+    fn test_missing_entrypoint() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("deploy.sh"),
+            "#!/bin/bash
+echo
+",
+        )
+        .unwrap();
+        let root = dir.path().to_path_buf();
+
+        let err = analyse_project(&root)
+            .unwrap()
+            .entrypoint("bin/missing.sh".into())
+            .build()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Entrypoint bin/missing.sh does not exist"),
+            "{}",
+            err
+        );
+        assert!(err.ends_with("  deploy.sh"), "{}", err);
+    }
+
+    #[test]
+    fn test_rust_project() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"hello\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("release.sh"), "#!/bin/sh\n").unwrap();
+        let root = dir.path().to_path_buf();
+
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert!(matches!(pack.ptype, PType::Rust));
+        assert_eq!(pack.entrypoint, PathBuf::from("target/release/hello"));
+
+        // Workspaces select their binary by name
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"cli\"]\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            analyse_project(&root).unwrap().build(),
+            Err(EnvyrError::DetectionFailed(_))
         ));
+        let pack = analyse_project(&root)
+            .unwrap()
+            .entrypoint("cli".into())
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("target/release/cli"));
     }
 
-    None
+    #[test]
+    fn test_python_module_entrypoint() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("tool").join("cli")).unwrap();
+        std::fs::write(dir.path().join("tool").join("__init__.py"), "").unwrap();
+        std::fs::write(dir.path().join("tool").join("cli").join("__main__.py"), "").unwrap();
+        std::fs::write(dir.path().join("tool").join("util.py"), "").unwrap();
+        let root = dir.path().to_path_buf();
+
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("-m tool.cli"));
+        assert!(matches!(pack.ptype, PType::Python));
+
+        let pack = analyse_project(&root)
+            .unwrap()
+            .entrypoint("-m tool.util".into())
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("-m tool.util"));
+        assert_eq!(pack.interpreter, "/usr/bin/env python");
+        assert!(matches!(
+            analyse_project(&root)
+                .unwrap()
+                .entrypoint("-m tool.missing".into())
+                .build(),
+            Err(EnvyrError::DetectionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_node_start_script() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("package.json"),
+            r#"{"name": "web", "scripts": {"start": "node server.js"}}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("server.js"), "").unwrap();
+        let root = dir.path().to_path_buf();
+
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("npm start"));
+        assert_eq!(pack.interpreter, "npm");
+        assert!(matches!(pack.ptype, PType::Node));
+
+        // main is preferred, start can still be picked
+        std::fs::write(
+            dir.path().join("package.json"),
+            r#"{"name": "web", "main": "server.js", "scripts": {"start": "node server.js"}}"#,
+        )
+        .unwrap();
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("server.js"));
+        let pack = analyse_project(&root)
+            .unwrap()
+            .entrypoint("npm start".into())
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("npm start"));
+
+        std::fs::write(dir.path().join("package.json"), r#"{"name": "web"}"#).unwrap();
+        assert!(analyse_project(&root)
+            .unwrap()
+            .entrypoint("npm start".into())
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_java_project() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("pom.xml"),
+            r#"<project>
+  <parent>
+    <artifactId>base</artifactId>
+    <version>2.0</version>
+  </parent>
+  <artifactId>report</artifactId>
+  <dependencies>
+    <dependency><artifactId>gson</artifactId><version>2.10</version></dependency>
+  </dependencies>
+</project>"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("mvnw"), "#!/bin/sh\n").unwrap();
+        let root = dir.path().to_path_buf();
+
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert!(matches!(pack.ptype, PType::Java));
+        assert_eq!(pack.entrypoint, PathBuf::from("target/report-2.0.jar"));
+        assert_eq!(pack.interpreter, "/usr/bin/env java");
+
+        // Another jar of the build is picked by name
+        let pack = analyse_project(&root)
+            .unwrap()
+            .entrypoint("report-2.0-cli".into())
+            .build()
+            .unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("target/report-2.0-cli.jar"));
+
+        std::fs::remove_file(dir.path().join("pom.xml")).unwrap();
+        std::fs::write(
+            dir.path().join("build.gradle.kts"),
+            "plugins { java }\nversion = \"1.3\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("settings.gradle.kts"),
+            "rootProject.name = \"tool\"\n",
+        )
+        .unwrap();
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("build/libs/tool-1.3.jar"));
+    }
+
+    #[test]
+    fn test_commands() {
+        let dir = tempfile::Builder::new().prefix("envyr").tempdir().unwrap();
+        for name in ["serve.py", "worker.py"] {
+            std::fs::write(dir.path().join(name), "print(1)\n").unwrap();
+        }
+        std::fs::write(
+            dir.path().join("envyr.toml"),
+            "entrypoint = \"serve.py\"\n[commands]\nworker = \"worker.py\"\n",
+        )
+        .unwrap();
+        let root = dir.path().to_path_buf();
+        let pack = analyse_project(&root).unwrap().build().unwrap();
+        assert_eq!(pack.entrypoint, PathBuf::from("serve.py"));
+        std::fs::create_dir(dir.path().join(".envyr")).unwrap();
+        pack.save(dir.path()).unwrap();
+        let pack = Pack::load(dir.path()).unwrap();
+        assert_eq!(pack.commands["worker"], PathBuf::from("worker.py"));
+
+        let meta_file = dir.path().join(".envyr").join("meta.json");
+        let meta = std::fs::read_to_string(&meta_file)
+            .unwrap()
+            .replace("\"worker.py\"", "1");
+        std::fs::write(&meta_file, meta).unwrap();
+        let err = Pack::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("`commands.worker` must be string"), "{}", err);
+    }
 }
@@ -1,38 +1,181 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::debug;
+
+// Templates in ~/.envyr/templates override the embedded ones below.
+pub const DOCKERFILE_TEMPLATE_FILE: &str = "Dockerfile.hbs";
+pub const DOCKERIGNORE_TEMPLATE_FILE: &str = "dockerignore.hbs";
+
+pub fn templates_dir() -> Option<PathBuf> {
+    home::home_dir().map(|home| home.join(".envyr").join("templates"))
+}
+
+// Reads `file` from the templates dir if present, else returns the default.
+pub fn load_template(
+    dir: Option<&Path>,
+    file: &str,
+    default: &'static str,
+) -> Result<Cow<'static, str>> {
+    match dir.map(|dir| dir.join(file)).filter(|path| path.exists()) {
+        Some(path) => {
+            debug!("Using template {}", path.display());
+            Ok(Cow::Owned(std::fs::read_to_string(path)?))
+        }
+        None => Ok(Cow::Borrowed(default)),
+    }
+}
+
+pub fn dockerfile_template() -> Result<Cow<'static, str>> {
+    load_template(
+        templates_dir().as_deref(),
+        DOCKERFILE_TEMPLATE_FILE,
+        TEMPLATE_DOCKERFILE,
+    )
+}
+
+pub fn dockerignore_template() -> Result<Cow<'static, str>> {
+    load_template(
+        templates_dir().as_deref(),
+        DOCKERIGNORE_TEMPLATE_FILE,
+        DOCKER_IGNORE,
+    )
+}
+
 pub static TEMPLATE_DOCKERFILE: &str = r#"
+{{#if (eq ptype "Rust")}}
+# Envyr Build
+FROM rust:alpine AS build
+RUN apk add --no-cache musl-dev
+WORKDIR /envyr/app
+ADD . /envyr/app
+RUN {{{secret_mounts}}}cargo build --release --bin {{rust_bin}}
+
+{{/if}}
+{{#if (eq ptype "Java")}}
+# Envyr Build
+{{#if (eq java_build "gradle")}}
+FROM gradle:jdk17 AS build
+WORKDIR /envyr/app
+ADD . /envyr/app
+RUN {{{secret_mounts}}}gradle --no-daemon -q build -x test
+{{else}}
+FROM maven:3-eclipse-temurin-17 AS build
+WORKDIR /envyr/app
+ADD . /envyr/app
+RUN {{{secret_mounts}}}mvn -B -q package -DskipTests
+{{/if}}
+
+{{/if}}
 # Envyr Base
+{{#if base_image}}
+FROM {{base_image}}
+{{else}}
 {{#if (eq ptype "Python")}}
 FROM python:3.11-alpine
 {{else}}
 {{#if (eq ptype "Node")}}
 FROM node:alpine
 {{else}}
+{{#if (eq ptype "Php")}}
+FROM php:cli-alpine
+{{else}}
+{{#if (eq ptype "Perl")}}
+FROM perl:slim
+{{else}}
+{{#if (eq ptype "Java")}}
+FROM eclipse-temurin:17-jre-alpine
+{{else}}
 FROM alpine
 {{/if}}
 {{/if}}
+{{/if}}
+{{/if}}
+{{/if}}
+{{/if}}
 
 # Base Deps
+{{#if (eq ptype "Perl")}}
+RUN apt-get update && apt-get install -y --no-install-recommends ca-certificates bash {{#each os_deps}} {{this}} {{/each}} && rm -rf /var/lib/apt/lists/*
+{{else}}
 RUN apk add --no-cache ca-certificates bash
 
 # Os Level Deps if any
 {{#if os_deps}}
 RUN apk add --no-cache {{#each os_deps}} {{this}} {{/each}}
 {{/if}}
+{{/if}}
 
 WORKDIR /envyr/app
 # Install Type Specific Deps
 {{#if type_reqs}}
 {{#if (eq ptype "Python")}}
-ADD ./requirements.txt /envyr/app/requirements.txt
-RUN pip install -r requirements.txt
+{{#if (eq python_installer "poetry")}}
+{{{manifest_add}}} ./pyproject.toml ./poetry.lock /envyr/app/
+RUN {{{secret_mounts}}}pip install poetry && poetry config virtualenvs.create false && poetry install --no-root --no-interaction
+{{else}}
+{{#if (eq python_installer "pipenv")}}
+{{{manifest_add}}} ./Pipfile ./Pipfile.lock /envyr/app/
+RUN {{{secret_mounts}}}pip install pipenv && pipenv install --system --deploy
+{{else}}
+{{#each requirements}}
+{{{../manifest_add}}} ./{{this}} /envyr/app/{{this}}
+{{/each}}
+RUN {{{secret_mounts}}}pip install{{#each requirements}} -r {{this}}{{/each}}
+{{/if}}
+{{/if}}
+{{/if}}
+{{#if (eq ptype "Php")}}
+RUN apk add --no-cache git unzip
+COPY --from=composer:2 /usr/bin/composer /usr/bin/composer
+{{{manifest_add}}} ./composer.json{{#if composer_lock}} ./composer.lock{{/if}} /envyr/app/
+RUN {{{secret_mounts}}}composer install --no-dev --no-interaction --no-scripts
+{{/if}}
+{{#if (eq ptype "Perl")}}
+{{{manifest_add}}} ./cpanfile /envyr/app/cpanfile
+RUN {{{secret_mounts}}}cpanm --notest --installdeps .
 {{/if}}
 {{#if (eq ptype "Node")}}
-ADD ./package.json /envyr/app/package.json
-RUN npm install
+{{#if (eq node_installer "pnpm")}}
+{{{manifest_add}}} ./package.json ./pnpm-lock.yaml /envyr/app/
+RUN {{{secret_mounts}}}npm install -g pnpm && pnpm install --frozen-lockfile
+{{else}}
+{{#if (eq node_installer "yarn")}}
+{{{manifest_add}}} ./package.json ./yarn.lock /envyr/app/
+RUN {{{secret_mounts}}}yarn install --frozen-lockfile
+{{else}}
+{{{manifest_add}}} ./package.json{{#if package_lock}} ./package-lock.json{{/if}} /envyr/app/
+RUN {{{secret_mounts}}}npm {{#if package_lock}}ci{{else}}install{{/if}}
+{{/if}}
+{{/if}}
 {{/if}}
 {{/if}}
 
+{{#if (eq ptype "Rust")}}
+COPY --from=build /envyr/app/{{entrypoint}} /envyr/app/{{entrypoint}}
+ENTRYPOINT ["/envyr/app/{{entrypoint}}"]
+{{else}}
+{{#if (eq ptype "Java")}}
+COPY --from=build /envyr/app/{{entrypoint}} /envyr/app/{{entrypoint}}
+ENTRYPOINT ["{{interpreter}}", {{#each interpreter_args}}{{{this}}}, {{/each}}{{#each pre_args}}{{{this}}}, {{/each}}"-jar", "/envyr/app/{{entrypoint}}"]
+{{else}}
 ADD . /envyr/app
-ENTRYPOINT ["{{interpreter}}", "{{entrypoint}}"]
+{{#if typescript}}
+RUN npx --yes -p typescript tsc{{#unless tsconfig}} {{ts_source}}{{/unless}}
+{{/if}}
+{{#if npm_start}}
+ENTRYPOINT ["npm", "start", "--"]
+{{else}}
+{{#if module}}
+ENV PYTHONPATH=/envyr/app
+ENTRYPOINT ["{{interpreter}}", {{#each interpreter_args}}{{{this}}}, {{/each}}{{#each pre_args}}{{{this}}}, {{/each}}"-m", "{{module}}"]
+{{else}}
+ENTRYPOINT ["{{interpreter}}", {{#each interpreter_args}}{{{this}}}, {{/each}}{{#each pre_args}}{{{this}}}, {{/each}}"/envyr/app/{{entrypoint}}"]
+{{/if}}
+{{/if}}
+{{/if}}
+{{/if}}
 "#;
 
 // To-Do
@@ -42,4 +185,77 @@ pub static DOCKER_IGNORE: &str = r#"
 **/.gitignore
 **/node_modules
 *.pyc
+{{#if (eq ptype "Rust")}}
+target
+{{/if}}
+{{#if (eq ptype "Java")}}
+target
+build
+.gradle
+{{/if}}
+"#;
+
+// A one-off Kubernetes Job running a pushed image. Values are JSON encoded,
+// which YAML reads as quoted strings.
+pub static TEMPLATE_K8S_JOB: &str = r#"apiVersion: batch/v1
+kind: Job
+metadata:
+  name: {{name}}
+spec:
+  backoffLimit: 0
+  template:
+    spec:
+      restartPolicy: Never
+      containers:
+        - name: {{name}}
+          image: {{{image}}}
+{{#if args}}
+          args: [{{#each args}}{{{this}}}{{#unless @last}}, {{/unless}}{{/each}}]
+{{/if}}
+{{#if env}}
+          env:
+{{#each env}}
+            - name: {{{this.name}}}
+              value: {{{this.value}}}
+{{/each}}
+{{/if}}
+{{#if ports}}
+          ports:
+{{#each ports}}
+            - containerPort: {{this.port}}
+              protocol: {{this.protocol}}
+{{/each}}
+{{/if}}
+{{#if requests}}
+          resources:
+            requests:
+{{#each requests}}
+              {{@key}}: {{{this}}}
+{{/each}}
+{{/if}}
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let template =
+            load_template(Some(dir.path()), DOCKERFILE_TEMPLATE_FILE, "default").unwrap();
+        assert_eq!(template, "default");
+        std::fs::write(
+            dir.path().join(DOCKERFILE_TEMPLATE_FILE),
+            "FROM {{base_image}}",
+        )
+        .unwrap();
+        let template =
+            load_template(Some(dir.path()), DOCKERFILE_TEMPLATE_FILE, "default").unwrap();
+        assert_eq!(template, "FROM {{base_image}}");
+        assert_eq!(
+            load_template(None, DOCKERFILE_TEMPLATE_FILE, "default").unwrap(),
+            "default"
+        );
+    }
+}
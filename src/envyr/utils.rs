@@ -4,16 +4,22 @@ use std::{
     fs::File,
     io::{self, BufRead},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 pub const PRIORITY_TOP: u8 = 0;
 pub const PRIORITY_LIKELY: u8 = 1;
 pub const PRIORITY_UNLIKELY: u8 = 2;
 pub const PRIORITY_LAST: u8 = 3;
 
+use super::error::EnvyrError;
 use super::package::PType;
 use anyhow::Result;
+use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use subprocess::{ExitStatus, Popen};
 
 // Checks if the file contains a python main.
 pub fn check_python_main(code: &str) -> Result<bool> {
@@ -33,27 +39,130 @@ pub fn check_python_exec_priority(f: &PathBuf) -> Result<u8> {
     Ok(PRIORITY_UNLIKELY)
 }
 
+// The module of a `-m package.module` entrypoint, run with `python -m`.
+pub fn python_module(entrypoint: &Path) -> Option<&str> {
+    let module = entrypoint.to_str()?.strip_prefix("-m")?.trim();
+    let valid = !module.is_empty()
+        && module.split('.').all(|part| {
+            part.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+        });
+    valid.then_some(module)
+}
+
+// The module a package's `__main__.py` runs as, e.g. `pkg.cli` for
+// pkg/cli/__main__.py. None for a __main__.py at the project root.
+pub fn python_main_module(relative: &Path) -> Option<String> {
+    if relative.file_name()? != "__main__.py" {
+        return None;
+    }
+    let package = relative
+        .parent()?
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?
+        .join(".");
+    python_module(Path::new(&format!("-m {}", package))).map(str::to_string)
+}
+
+// Whether a module entrypoint resolves to a file or package in the project.
+pub fn python_module_exists(project_root: &Path, module: &str) -> bool {
+    let path = project_root.join(module.replace('.', "/"));
+    path.with_extension("py").is_file() || path.join("__main__.py").is_file()
+}
+
+// Interpreters a README may show a script being run with.
+const README_INTERPRETERS: &[&str] = &["node", "bash", "sh", "php", "perl"];
+
+// Scripts README.md shows being run in its code blocks, e.g. `python main.py`
+// or `./run.sh`, along with the interpreter named. Only ones in the project
+// are kept.
+pub fn readme_entrypoints(project_root: &Path) -> Vec<(PathBuf, Option<String>)> {
+    let Ok(readme) = std::fs::read_to_string(project_root.join("README.md")) else {
+        return vec![];
+    };
+    let mut found: Vec<(PathBuf, Option<String>)> = vec![];
+    let mut in_block = false;
+    for line in readme.lines().map(str::trim) {
+        if line.starts_with("```") || line.starts_with("~~~") {
+            in_block = !in_block;
+            continue;
+        }
+        if !in_block {
+            continue;
+        }
+        let line = line.strip_prefix('$').unwrap_or(line);
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let python = |i: &str| i.starts_with("python");
+        let (entrypoint, interpreter) = match words[..] {
+            [interpreter, "-m", module, ..] if python(interpreter) => {
+                let entrypoint = PathBuf::from(format!("-m {}", module));
+                match python_module(&entrypoint)
+                    .is_some_and(|m| python_module_exists(project_root, m))
+                {
+                    true => (entrypoint, Some(interpreter)),
+                    false => continue,
+                }
+            }
+            [interpreter, script, ..]
+                if python(interpreter) || README_INTERPRETERS.contains(&interpreter) =>
+            {
+                (PathBuf::from(script), Some(interpreter))
+            }
+            [script, ..] if script.starts_with("./") => (PathBuf::from(script), None),
+            _ => continue,
+        };
+        let entrypoint = match entrypoint.strip_prefix(".") {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => entrypoint,
+        };
+        let inside = entrypoint
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        let exists =
+            python_module(&entrypoint).is_some() || project_root.join(&entrypoint).is_file();
+        if inside && exists && !found.iter().any(|(e, _)| *e == entrypoint) {
+            found.push((entrypoint, interpreter.map(str::to_string)));
+        }
+    }
+    found
+}
+
 // Returns the interpretter of the file if a shebang is found on top.
 pub fn check_shebang_file(file: &PathBuf) -> Result<Option<String>> {
     let file = File::open(file)?;
     let mut reader = io::BufReader::new(file);
     let mut line = vec![];
     _ = reader.read_until(b'\n', &mut line)?;
-    let line = String::from_utf8(line)?.trim().to_string();
-    if line.starts_with("#!") {
-        return Ok(Some(
-            line.strip_prefix("#!").unwrap_or_default().to_string(),
-        ));
+    // Strip line endings (including CRLF from Windows authored files) and any
+    // spacing around the interpreter, e.g. `#! /bin/sh`.
+    let line = String::from_utf8(line)?;
+    let line = line.trim_end_matches(['\r', '\n']).trim();
+    if let Some(interpreter) = line.strip_prefix("#!") {
+        return Ok(Some(interpreter.trim().to_string()));
     }
     Ok(None)
 }
 
-pub fn map_extension_to_ptype(ext: &str) -> Option<PType> {
-    match ext {
-        "py" => Some(PType::Python),
-        "sh" => Some(PType::Shell),
-        "js" => Some(PType::Node),
-        "ts" => Some(PType::Node),
+// Interpreters inside a virtualenv only exist on the machine that created it.
+pub fn is_venv_interpreter(interpreter: &str) -> bool {
+    let program = interpreter.split_whitespace().next().unwrap_or_default();
+    Path::new(program).parent().is_some_and(|dir| {
+        dir.components().any(|c| {
+            matches!(
+                c.as_os_str().to_str(),
+                Some(".venv" | "venv" | "virtualenvs" | ".virtualenvs")
+            )
+        })
+    })
+}
+
+// Maps interpreters the extensions can't tell apart, e.g. `/usr/bin/perl`.
+pub fn map_interpreter_to_ptype(interpreter: &str) -> Option<PType> {
+    let program = interpreter.trim_start_matches("/usr/bin/env ");
+    let program = program.split_whitespace().next()?;
+    match program.rsplit('/').next()? {
+        "perl" => Some(PType::Perl),
         _ => None,
     }
 }
@@ -66,6 +175,154 @@ pub fn check_package_json(project_root: &Path) -> bool {
     false
 }
 
+pub fn check_composer_json(project_root: &Path) -> bool {
+    project_root.join("composer.json").exists()
+}
+
+// Returns the package manager matching the project's lockfile, npm without one.
+pub fn detect_node_installer(project_root: &Path) -> String {
+    if project_root.join("pnpm-lock.yaml").exists() {
+        return "pnpm".to_string();
+    }
+    if project_root.join("yarn.lock").exists() {
+        return "yarn".to_string();
+    }
+    "npm".to_string()
+}
+
+pub fn check_package_lock(project_root: &Path) -> bool {
+    project_root.join("package-lock.json").exists()
+}
+
+pub fn check_composer_lock(project_root: &Path) -> bool {
+    project_root.join("composer.lock").exists()
+}
+
+pub fn check_cpanfile(project_root: &Path) -> bool {
+    project_root.join("cpanfile").exists()
+}
+
+pub fn check_cargo_toml(project_root: &Path) -> bool {
+    project_root.join("Cargo.toml").exists()
+}
+
+// Maven or Gradle, by the build file at the project root.
+pub fn detect_java_build(project_root: &Path) -> Option<String> {
+    if project_root.join("pom.xml").exists() {
+        return Some("maven".to_string());
+    }
+    ["build.gradle", "build.gradle.kts"]
+        .iter()
+        .any(|f| project_root.join(f).exists())
+        .then(|| "gradle".to_string())
+}
+
+// The jar a Maven or Gradle build produces, relative to the project. Only
+// literal names and versions are understood, not properties or multi-module
+// builds, which need --entrypoint.
+pub fn detect_java_jar(project_root: &Path) -> Option<PathBuf> {
+    match detect_java_build(project_root)?.as_str() {
+        "maven" => {
+            let pom = std::fs::read_to_string(project_root.join("pom.xml")).ok()?;
+            let parent = xml_block(&pom, "parent").unwrap_or_default();
+            let build = xml_block(&pom, "build").unwrap_or_default();
+            // Only the project's own coordinates, not those of its parent/deps
+            let mut own = pom.clone();
+            for tag in [
+                "parent",
+                "dependencies",
+                "dependencyManagement",
+                "build",
+                "profiles",
+            ] {
+                own = strip_xml_block(&own, tag);
+            }
+            if xml_text(&own, "packaging").is_some_and(|p| p != "jar") {
+                return None;
+            }
+            let name = match xml_text(build, "finalName") {
+                Some(name) => name.to_string(),
+                None => {
+                    let version = xml_text(&own, "version").or(xml_text(parent, "version"))?;
+                    format!("{}-{}", xml_text(&own, "artifactId")?, version)
+                }
+            };
+            (!name.contains("${")).then(|| java_jar_path("maven", &name))
+        }
+        _ => {
+            let settings = ["settings.gradle", "settings.gradle.kts"]
+                .iter()
+                .find_map(|f| std::fs::read_to_string(project_root.join(f)).ok())
+                .unwrap_or_default();
+            let build = ["build.gradle", "build.gradle.kts"]
+                .iter()
+                .find_map(|f| std::fs::read_to_string(project_root.join(f)).ok())
+                .unwrap_or_default();
+            let name = match gradle_assignment(&settings, "rootProject.name") {
+                Some(name) => name.to_string(),
+                None => project_root
+                    .canonicalize()
+                    .ok()?
+                    .file_name()?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            let name = match gradle_assignment(&build, "version") {
+                Some(version) => format!("{}-{}", name, version),
+                None => name,
+            };
+            Some(java_jar_path("gradle", &name))
+        }
+    }
+}
+
+// Where Maven (target) or Gradle (build/libs) puts a jar, relative to the project.
+pub fn java_jar_path(build: &str, name: &str) -> PathBuf {
+    let dir = match build {
+        "gradle" => Path::new("build").join("libs"),
+        _ => PathBuf::from("target"),
+    };
+    match name.ends_with(".jar") {
+        true => dir.join(name),
+        false => dir.join(format!("{}.jar", name)),
+    }
+}
+
+// The contents of the first <tag>...</tag> element.
+fn xml_block<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..start + end])
+}
+
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    xml_block(xml, tag).map(str::trim)
+}
+
+fn strip_xml_block(xml: &str, tag: &str) -> String {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    match (xml.find(&open), xml.find(&close)) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &xml[..start], &xml[end + close.len()..])
+        }
+        _ => xml.to_string(),
+    }
+}
+
+// The quoted value of a top-level `key = 'value'` line in a Gradle script.
+fn gradle_assignment<'a>(script: &'a str, key: &str) -> Option<&'a str> {
+    script.lines().find_map(|line| {
+        let value = line
+            .trim()
+            .strip_prefix(key)?
+            .trim_start()
+            .strip_prefix('=')?;
+        let value = value.trim();
+        let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+        value[1..].split(quote).next()
+    })
+}
+
 pub fn check_requirements_txt(project_root: &Path) -> bool {
     let requirements_txt = project_root.join("requirements.txt");
     if requirements_txt.exists() {
@@ -74,6 +331,127 @@ pub fn check_requirements_txt(project_root: &Path) -> bool {
     false
 }
 
+// Secondary requirements files installed along with requirements.txt.
+const EXTRA_REQUIREMENTS: &[&str] = &["requirements-dev.txt", "dev-requirements.txt"];
+
+// Lists requirements.txt and any secondary requirements files next to it.
+pub fn detect_requirements_files(project_root: &Path) -> Vec<PathBuf> {
+    if !check_requirements_txt(project_root) {
+        return vec![];
+    }
+    std::iter::once("requirements.txt")
+        .chain(EXTRA_REQUIREMENTS.iter().copied())
+        .filter(|f| project_root.join(f).is_file())
+        .map(PathBuf::from)
+        .collect()
+}
+
+pub fn check_poetry_lock(project_root: &Path) -> bool {
+    project_root.join("poetry.lock").exists() && project_root.join("pyproject.toml").exists()
+}
+
+pub fn check_pipfile_lock(project_root: &Path) -> bool {
+    project_root.join("Pipfile.lock").exists() && project_root.join("Pipfile").exists()
+}
+
+// Returns the tool used to install python dependencies for the project.
+// Lockfiles take precedence over a plain requirements.txt.
+pub fn detect_python_installer(project_root: &Path) -> Option<String> {
+    if check_poetry_lock(project_root) {
+        return Some("poetry".to_string());
+    }
+    if check_pipfile_lock(project_root) {
+        return Some("pipenv".to_string());
+    }
+    if check_requirements_txt(project_root) {
+        return Some("pip".to_string());
+    }
+    None
+}
+
+// Checks a directory for the dependency manifests of the given ptype.
+pub fn check_manifests(dir: &Path, ptype: &PType) -> bool {
+    match ptype {
+        PType::Python => detect_python_installer(dir).is_some(),
+        PType::Node => check_package_json(dir),
+        PType::Php => check_composer_json(dir),
+        PType::Perl => check_cpanfile(dir),
+        PType::Rust => check_cargo_toml(dir),
+        PType::Java => detect_java_build(dir).is_some(),
+        _ => false,
+    }
+}
+
+// When the project has no manifests of its own, walks up towards the
+// repository root (the closest parent holding .git/.hg) and returns the path,
+// relative to the project, of the first directory that has them.
+pub fn find_deps_root(project_root: &Path, ptype: &PType) -> Option<PathBuf> {
+    if check_manifests(project_root, ptype) {
+        return None;
+    }
+    let is_repo_root = |dir: &Path| dir.join(".git").exists() || dir.join(".hg").exists();
+    let root = project_root.canonicalize().ok()?;
+    // Never look outside of a repository
+    if !root.ancestors().any(is_repo_root) {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for dir in root.ancestors() {
+        if is_repo_root(dir) {
+            break;
+        }
+        relative.push("..");
+        let parent = dir.parent()?;
+        if check_manifests(parent, ptype) {
+            return Some(relative);
+        }
+    }
+    None
+}
+
+// Reads the python version pinned in .python-version (pyenv style), if it
+// names a plain version such as 3.11 or 3.11.4.
+pub fn read_python_version(project_root: &Path) -> Option<String> {
+    let contents = std::fs::read_to_string(project_root.join(".python-version")).ok()?;
+    let version = contents
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('#'))?;
+    if !version.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        log::warn!(
+            "Ignoring unsupported .python-version entry '{}', expected a version like 3.11.",
+            version
+        );
+        return None;
+    }
+    Some(version.to_string())
+}
+
+pub fn check_tsconfig_json(project_root: &Path) -> bool {
+    project_root.join("tsconfig.json").exists()
+}
+
+// Returns where tsc writes the compiled js for a ts entrypoint, honoring
+// outDir/rootDir from tsconfig.json when set.
+pub fn typescript_output(project_root: &Path, entrypoint: &Path) -> PathBuf {
+    let js = entrypoint.with_extension("js");
+    let tsconfig = std::fs::read_to_string(project_root.join("tsconfig.json"))
+        .ok()
+        .and_then(|c| serde_json::from_str::<Value>(&c).ok());
+    let Some(tsconfig) = tsconfig else {
+        return js;
+    };
+    let Some(out_dir) = tsconfig["compilerOptions"]["outDir"].as_str() else {
+        return js;
+    };
+    let root_dir = tsconfig["compilerOptions"]["rootDir"]
+        .as_str()
+        .unwrap_or_default()
+        .trim_start_matches("./");
+    let relative = js.strip_prefix(root_dir).unwrap_or(&js);
+    PathBuf::from(out_dir.trim_start_matches("./")).join(relative)
+}
+
 pub fn detect_main_node(project_root: &Path) -> Option<PathBuf> {
     if !check_package_json(project_root) {
         return None;
@@ -93,6 +471,40 @@ pub fn detect_main_node(project_root: &Path) -> Option<PathBuf> {
     }
 }
 
+// The entrypoint running package.json's start script rather than a file.
+pub const NPM_START: &str = "npm start";
+
+pub fn is_npm_start(entrypoint: &Path) -> bool {
+    entrypoint == Path::new(NPM_START)
+}
+
+// Whether package.json has a start script to run with `npm start`.
+pub fn detect_node_start(project_root: &Path) -> bool {
+    std::fs::read_to_string(project_root.join("package.json"))
+        .ok()
+        .and_then(|package_json| serde_json::from_str::<Value>(&package_json).ok())
+        .is_some_and(|v| v["scripts"]["start"].is_string())
+}
+
+// The binary a cargo project builds: its first [[bin]] target, else the
+// package itself. Workspaces without a root package have none.
+pub fn detect_rust_binary(project_root: &Path) -> Option<String> {
+    let cargo_toml = std::fs::read_to_string(project_root.join("Cargo.toml")).ok()?;
+    let manifest: toml::Table = toml::from_str(&cargo_toml).ok()?;
+    let bin = manifest
+        .get("bin")
+        .and_then(|bins| bins.as_array()?.first()?.get("name")?.as_str());
+    let package = manifest
+        .get("package")
+        .and_then(|package| package.get("name")?.as_str());
+    bin.or(package).map(str::to_string)
+}
+
+// Where cargo puts a release binary, relative to the project.
+pub fn rust_binary_path(name: &str) -> PathBuf {
+    Path::new("target").join("release").join(name)
+}
+
 #[derive(Serialize, Deserialize)]
 struct PackDeps {
     deps: Vec<String>,
@@ -111,6 +523,90 @@ pub fn check_bash_dependencies(script_file: &Path) -> Result<Vec<String>> {
     Ok(deps.deps)
 }
 
+// Commands commonly shelled out to from scripts, mapped to the alpine package
+// providing them. Anything not listed is ignored to avoid false positives.
+const COMMAND_PACKAGES: &[(&str, &str)] = &[
+    ("aws", "aws-cli"),
+    ("convert", "imagemagick"),
+    ("curl", "curl"),
+    ("ffmpeg", "ffmpeg"),
+    ("ffprobe", "ffmpeg"),
+    ("gcc", "gcc"),
+    ("git", "git"),
+    ("jq", "jq"),
+    ("magick", "imagemagick"),
+    ("make", "make"),
+    ("pandoc", "pandoc"),
+    ("psql", "postgresql-client"),
+    ("rsync", "rsync"),
+    ("scp", "openssh-client"),
+    ("sqlite3", "sqlite"),
+    ("ssh", "openssh-client"),
+    ("unzip", "unzip"),
+    ("wget", "wget"),
+    ("zip", "zip"),
+];
+
+const PYTHON_COMMAND_CALLS: &[&str] = &[
+    "subprocess.run(",
+    "subprocess.call(",
+    "subprocess.check_call(",
+    "subprocess.check_output(",
+    "subprocess.Popen(",
+    "os.system(",
+    "os.popen(",
+];
+
+const NODE_COMMAND_CALLS: &[&str] = &[
+    "exec(",
+    "execSync(",
+    "execFile(",
+    "execFileSync(",
+    "spawn(",
+    "spawnSync(",
+];
+
+// Scans python code for commands run through subprocess/os.system and
+// returns the os packages providing them.
+pub fn check_python_os_deps(code: &str) -> Vec<String> {
+    scan_command_calls(code, PYTHON_COMMAND_CALLS)
+}
+
+// Scans node code for commands run through child_process and returns the os
+// packages providing them.
+pub fn check_node_os_deps(code: &str) -> Vec<String> {
+    if !code.contains("child_process") {
+        return vec![];
+    }
+    scan_command_calls(code, NODE_COMMAND_CALLS)
+}
+
+// Only string literals directly passed to the call (or as the first element
+// of a list) are considered, e.g. `subprocess.run(["git", ...])`.
+fn scan_command_calls(code: &str, calls: &[&str]) -> Vec<String> {
+    let mut deps = vec![];
+    for call in calls {
+        for (idx, _) in code.match_indices(call) {
+            let rest = code[idx + call.len()..].trim_start();
+            let rest = rest.strip_prefix('[').unwrap_or(rest).trim_start();
+            let Some(quote) = rest.chars().next().filter(|c| "\"'`".contains(*c)) else {
+                continue;
+            };
+            let Some(literal) = rest[1..].split(quote).next() else {
+                continue;
+            };
+            let command = literal.split_whitespace().next().unwrap_or_default();
+            let command = command.rsplit('/').next().unwrap_or_default();
+            if let Some((_, pkg)) = COMMAND_PACKAGES.iter().find(|(c, _)| *c == command) {
+                if !deps.contains(&pkg.to_string()) {
+                    deps.push(pkg.to_string());
+                }
+            }
+        }
+    }
+    deps
+}
+
 pub fn create_requirements_txt(project_root: &Path) -> Result<()> {
     // Assume pipreqs exists
     let output = std::process::Command::new("envyr")
@@ -134,3 +630,473 @@ pub fn create_requirements_txt(project_root: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+// Quotes an argument so it can be pasted into a POSIX shell.
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+pub fn shell_join(args: &[String]) -> String {
+    args.iter()
+        .map(|a| shell_quote(a))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Maps a child's exit status to a process exit code, using 128+signal for
+// signal-terminated children like shells do.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match status {
+        ExitStatus::Exited(code) => code as i32,
+        ExitStatus::Signaled(signal) => 128 + signal as i32,
+        ExitStatus::Other(code) => code,
+        ExitStatus::Undetermined => 1,
+    }
+}
+
+// Runs a command like Command::output, killing it if it is still running at
+// the deadline. Nothing is started once the deadline has passed.
+pub fn output_until(
+    cmd: &mut std::process::Command,
+    deadline: Option<Instant>,
+) -> Result<std::process::Output, EnvyrError> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(cmd.output()?),
+    };
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let expired = || {
+        EnvyrError::Timeout(format!(
+            "--total-timeout expired while running {}.",
+            program
+        ))
+    };
+    if Instant::now() >= deadline {
+        return Err(expired());
+    }
+    let mut child = cmd
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    // Drained on threads so a chatty child never blocks on a full pipe.
+    let drain = |pipe: Option<Box<dyn io::Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut pipe) = pipe {
+                _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            _ = child.kill();
+            child.wait()?;
+            return Err(expired());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    Ok(std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+// Reads the patterns of a project's .dockerignore as (negated, pattern) pairs.
+pub fn read_dockerignore(project_root: &Path) -> Vec<(bool, String)> {
+    read_ignore_file(&project_root.join(".dockerignore"))
+}
+
+// Reads a file of .dockerignore patterns, e.g. .envyrignore.
+pub fn read_ignore_file(path: &Path) -> Vec<(bool, String)> {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| match l.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, l),
+        })
+        .map(|(negated, pattern)| {
+            let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+            (negated, pattern.to_string())
+        })
+        .collect()
+}
+
+// Matches a path relative to the context root like docker does: the last
+// matching pattern wins, and a pattern matching a directory covers its contents.
+pub fn is_dockerignored(relative: &Path, patterns: &[(bool, String)]) -> bool {
+    let segments = relative
+        .components()
+        .filter_map(|c| c.as_os_str().to_str())
+        .collect::<Vec<_>>();
+    let mut ignored = false;
+    for (negated, pattern) in patterns {
+        let pattern = pattern.split('/').collect::<Vec<_>>();
+        if (1..=segments.len()).any(|n| glob_segments(&pattern, &segments[..n])) {
+            ignored = !negated;
+        }
+    }
+    ignored
+}
+
+fn glob_segments(pattern: &[&str], segments: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => segments.is_empty(),
+        Some((&"**", rest)) => (0..=segments.len()).any(|i| glob_segments(rest, &segments[i..])),
+        Some((first, rest)) => segments.split_first().is_some_and(|(s, tail)| {
+            glob(first.as_bytes(), s.as_bytes()) && glob_segments(rest, tail)
+        }),
+    }
+}
+
+// `*` and `?` within a single path segment.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob(rest, &name[1..]),
+    }
+}
+
+// SELinux exposes its enforcement state here when it is enabled.
+pub fn selinux_enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+// Checks whether an executable with this name is on PATH.
+pub fn on_path(program: &str) -> bool {
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths).any(|dir| {
+        let candidate = dir.join(program);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            candidate
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        }
+        #[cfg(not(unix))]
+        {
+            candidate.is_file() || candidate.with_extension("exe").is_file()
+        }
+    })
+}
+
+// Formats seconds since the unix epoch as a UTC `YYYY-MM-DD HH:MM:SS`.
+pub fn format_utc(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Wall clock time spent in each phase of a run, reported with --timings.
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    pub fetch_ms: u128,
+    pub build_ms: u128,
+    pub run_ms: u128,
+}
+
+// Catches SIGINT/SIGTERM from here on, storing the received signal instead
+// of exiting so it can be passed on to a child.
+pub fn register_interrupts() -> Result<Arc<AtomicUsize>> {
+    let received = Arc::new(AtomicUsize::new(0));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register_usize(signal, Arc::clone(&received), signal as usize)?;
+    }
+    Ok(received)
+}
+
+// Waits for the child, forwarding an interrupt to it. If it is still running
+// after the grace period, or past the deadline, `on_timeout` is called and the
+// child killed.
+pub fn wait_forwarding_interrupts(
+    p: &mut Popen,
+    received: &AtomicUsize,
+    grace: Duration,
+    deadline: Option<Instant>,
+    on_timeout: impl FnOnce(),
+) -> Result<ExitStatus> {
+    loop {
+        if let Some(status) = p.wait_timeout(Duration::from_millis(100))? {
+            return Ok(status);
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            debug!("Deadline passed, stopping the child");
+            on_timeout();
+            p.kill()?;
+            p.wait()?;
+            return Err(EnvyrError::Timeout(
+                "The package was still running when --total-timeout expired.".to_string(),
+            )
+            .into());
+        }
+        let signal = received.swap(0, Ordering::SeqCst);
+        if signal == 0 {
+            continue;
+        }
+        debug!("Forwarding signal {} to the child", signal);
+        forward_signal(p, signal as i32)?;
+        if let Some(status) = p.wait_timeout(grace)? {
+            return Ok(status);
+        }
+        debug!("Child still running after {:?}, stopping it", grace);
+        on_timeout();
+        p.kill()?;
+        return Ok(p.wait()?);
+    }
+}
+
+#[cfg(unix)]
+fn forward_signal(p: &Popen, signal: i32) -> Result<()> {
+    use subprocess::unix::PopenExt;
+    Ok(p.send_signal(signal)?)
+}
+
+#[cfg(not(unix))]
+fn forward_signal(p: &mut Popen, _signal: i32) -> Result<()> {
+    Ok(p.terminate()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("-v"), "-v");
+        assert_eq!(shell_quote("/root:/root"), "/root:/root");
+        assert_eq!(shell_quote("KEY=a b"), "'KEY=a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_check_shebang_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("run.py");
+        std::fs::write(&script, "#!/usr/bin/env python3\r\nprint('hi')\r\n").unwrap();
+        assert_eq!(
+            check_shebang_file(&script).unwrap(),
+            Some("/usr/bin/env python3".to_string())
+        );
+
+        std::fs::write(&script, "#! /bin/sh\r\n").unwrap();
+        assert_eq!(
+            check_shebang_file(&script).unwrap(),
+            Some("/bin/sh".to_string())
+        );
+
+        std::fs::write(&script, "print('hi')\r\n").unwrap();
+        assert_eq!(check_shebang_file(&script).unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_deps_root() {
+        let repo = tempfile::tempdir().unwrap();
+        let app = repo.path().join("services").join("app");
+        std::fs::create_dir_all(&app).unwrap();
+        std::fs::write(repo.path().join("requirements.txt"), "").unwrap();
+        // Not inside a repository
+        assert_eq!(find_deps_root(&app, &PType::Python), None);
+
+        std::fs::create_dir(repo.path().join(".git")).unwrap();
+        assert_eq!(
+            find_deps_root(&app, &PType::Python),
+            Some(PathBuf::from("../.."))
+        );
+        assert_eq!(find_deps_root(&app, &PType::Node), None);
+
+        std::fs::write(app.join("requirements.txt"), "").unwrap();
+        assert_eq!(find_deps_root(&app, &PType::Python), None);
+    }
+
+    #[test]
+    fn test_read_python_version() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_python_version(dir.path()), None);
+        std::fs::write(dir.path().join(".python-version"), "# pinned\n3.10.12\n").unwrap();
+        assert_eq!(read_python_version(dir.path()), Some("3.10.12".to_string()));
+        std::fs::write(dir.path().join(".python-version"), "pypy3.9\n").unwrap();
+        assert_eq!(read_python_version(dir.path()), None);
+    }
+
+    #[test]
+    fn test_typescript_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = Path::new("src/index.ts");
+        assert_eq!(
+            typescript_output(dir.path(), entry),
+            PathBuf::from("src/index.js")
+        );
+
+        std::fs::write(
+            dir.path().join("tsconfig.json"),
+            r#"{"compilerOptions": {"outDir": "./dist", "rootDir": "./src"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            typescript_output(dir.path(), entry),
+            PathBuf::from("dist/index.js")
+        );
+    }
+
+    #[test]
+    fn test_check_python_os_deps() {
+        let code = r#"
+import os, subprocess
+subprocess.run(["ffmpeg", "-i", src, dst])
+subprocess.check_output("git rev-parse HEAD", shell=True)
+os.system('/usr/bin/git status')
+subprocess.run([sys.executable, "-m", "pip"])
+subprocess.run(["python", "other.py"])
+"#;
+        assert_eq!(check_python_os_deps(code), ["ffmpeg", "git"]);
+    }
+
+    #[test]
+    fn test_check_node_os_deps() {
+        let code = r#"
+const { execSync } = require("child_process");
+execSync(`convert ${input} out.png`);
+spawn("curl", ["-s", url]);
+"#;
+        assert_eq!(check_node_os_deps(code), ["imagemagick", "curl"]);
+        assert!(check_node_os_deps(r#"execSync("git")"#).is_empty());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(ExitStatus::Exited(0)), 0);
+        assert_eq!(exit_code(ExitStatus::Exited(42)), 42);
+        assert_eq!(exit_code(ExitStatus::Signaled(2)), 130);
+    }
+
+    #[test]
+    fn test_is_dockerignored() {
+        let patterns = vec![
+            (false, "**/.git".to_string()),
+            (false, "*.pyc".to_string()),
+            (false, "build".to_string()),
+            (false, "docs/*.md".to_string()),
+            (true, "docs/README.md".to_string()),
+        ];
+        assert!(is_dockerignored(Path::new(".git/config"), &patterns));
+        assert!(is_dockerignored(Path::new("sub/.git"), &patterns));
+        assert!(is_dockerignored(Path::new("main.pyc"), &patterns));
+        assert!(!is_dockerignored(Path::new("sub/main.pyc"), &patterns));
+        assert!(is_dockerignored(Path::new("build/out/x"), &patterns));
+        assert!(is_dockerignored(Path::new("docs/guide.md"), &patterns));
+        assert!(!is_dockerignored(Path::new("docs/README.md"), &patterns));
+        assert!(!is_dockerignored(Path::new("main.py"), &patterns));
+    }
+
+    #[test]
+    fn test_on_path() {
+        assert!(on_path("sh"));
+        assert!(!on_path("envyr-surely-not-a-real-binary"));
+    }
+
+    #[test]
+    fn test_wait_forwarding_interrupts() {
+        let mut p = Popen::create(
+            &["sh", "-c", "trap 'exit 7' TERM; sleep 30 & wait"],
+            subprocess::PopenConfig::default(),
+        )
+        .unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let interrupt = Arc::clone(&received);
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            interrupt.store(signal_hook::consts::SIGTERM as usize, Ordering::SeqCst);
+        });
+        let status =
+            wait_forwarding_interrupts(&mut p, &received, Duration::from_secs(5), None, || {})
+                .unwrap();
+        assert_eq!(status, ExitStatus::Exited(7));
+
+        // Children ignoring the interrupt are stopped after the grace period
+        let mut p = Popen::create(
+            &["sh", "-c", "trap '' TERM; sleep 30 & wait"],
+            subprocess::PopenConfig::default(),
+        )
+        .unwrap();
+        received.store(signal_hook::consts::SIGTERM as usize, Ordering::SeqCst);
+        let mut stopped = false;
+        let status =
+            wait_forwarding_interrupts(&mut p, &received, Duration::from_millis(200), None, || {
+                stopped = true
+            })
+            .unwrap();
+        assert!(stopped);
+        assert_eq!(status, ExitStatus::Signaled(9));
+
+        // Children still running at the deadline are stopped
+        let mut p = Popen::create(&["sleep", "30"], subprocess::PopenConfig::default()).unwrap();
+        let deadline = Some(Instant::now() + Duration::from_millis(200));
+        let mut stopped = false;
+        let err =
+            wait_forwarding_interrupts(&mut p, &received, Duration::from_secs(5), deadline, || {
+                stopped = true
+            })
+            .unwrap_err();
+        assert!(stopped);
+        assert!(err.to_string().contains("--total-timeout"), "{}", err);
+    }
+
+    #[test]
+    fn test_output_until() {
+        let out = output_until(std::process::Command::new("echo").arg("hi"), None).unwrap();
+        assert_eq!(out.stdout, b"hi\n");
+        let deadline = Some(Instant::now() + Duration::from_secs(5));
+        let out = output_until(std::process::Command::new("echo").arg("hi"), deadline).unwrap();
+        assert_eq!(out.stdout, b"hi\n");
+
+        let start = Instant::now();
+        let deadline = Some(start + Duration::from_millis(200));
+        let err = output_until(std::process::Command::new("sleep").arg("30"), deadline);
+        assert!(matches!(err, Err(EnvyrError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
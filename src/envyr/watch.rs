@@ -0,0 +1,99 @@
+// Watches a local project for changes, for `run --watch`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use super::utils;
+
+// Edits arriving within this window of each other are handled as one change.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Written by envyr, package managers and interpreters rather than by hand.
+const GENERATED: &[&str] = &[".git", "node_modules", "__pycache__"];
+
+pub struct ProjectWatcher {
+    root: PathBuf,
+    patterns: Vec<(bool, String)>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl ProjectWatcher {
+    pub fn new(root: &Path) -> Result<Self> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        let mut patterns = utils::read_dockerignore(root);
+        patterns.extend(utils::read_ignore_file(&root.join(".envyrignore")));
+        Ok(Self {
+            root: root.to_path_buf(),
+            patterns,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    // Blocks until files change, returning them relative to the project root.
+    // Returns None once an interrupt is received.
+    pub fn wait(&self, received: &AtomicUsize) -> Result<Option<Vec<PathBuf>>> {
+        let mut changed: Vec<PathBuf> = vec![];
+        loop {
+            if received.load(Ordering::SeqCst) != 0 {
+                return Ok(None);
+            }
+            let timeout = match changed.is_empty() {
+                true => Duration::from_millis(100),
+                false => DEBOUNCE,
+            };
+            let event = match self.events.recv_timeout(timeout) {
+                Ok(event) => event?,
+                Err(RecvTimeoutError::Timeout) if changed.is_empty() => continue,
+                Err(RecvTimeoutError::Timeout) => return Ok(Some(changed)),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("The file watcher stopped unexpectedly."))
+                }
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            for path in event.paths {
+                let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                if !self.is_ignored(relative) && !changed.iter().any(|c| c == relative) {
+                    debug!("Changed: {}", relative.display());
+                    changed.push(relative.to_path_buf());
+                }
+            }
+        }
+    }
+
+    fn is_ignored(&self, relative: &Path) -> bool {
+        relative.starts_with(".envyr")
+            || relative == Path::new(".dockerignore")
+            || relative
+                .components()
+                .any(|c| GENERATED.iter().any(|g| c.as_os_str() == *g))
+            || utils::is_dockerignored(relative, &self.patterns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_ignores() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".envyrignore"), "out\n").unwrap();
+        let watcher = ProjectWatcher::new(dir.path()).unwrap();
+        assert!(watcher.is_ignored(Path::new(".envyr/Dockerfile")));
+        assert!(watcher.is_ignored(Path::new("pkg/__pycache__/mod.pyc")));
+        assert!(watcher.is_ignored(Path::new("out/report.csv")));
+        assert!(!watcher.is_ignored(Path::new("main.py")));
+    }
+}
@@ -5,9 +5,15 @@ use clap::{Args, Parser, Subcommand};
 use envyr::adapters::fetcher;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use std::io::{IsTerminal, Read};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Instant;
 
+use crate::envyr::config::{
+    add_host_key, cache_root, copy_out_key, env_map_key, fs_map_key, merge_maps, port_map_key,
+    ProjectConfig, UserConfig,
+};
 use crate::envyr::meta;
 
 #[derive(Debug, Args)]
@@ -33,9 +39,19 @@ struct GlobalOpts {
         help = "refresh code cache before running."
     )]
     refresh: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Tag the image with the checked out commit (short SHA) instead of --tag, so every commit gets its own image. Kept with aliases."
+    )]
+    tag_from_git: bool,
+
+    #[clap(flatten)]
+    fetch_opts: fetcher::FetchOpts,
 }
 
-#[derive(Debug, Args, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Args, Serialize, Deserialize, Clone)]
 struct OverrideOpts {
     #[arg(long, short)]
     name: Option<String>,
@@ -43,17 +59,157 @@ struct OverrideOpts {
     #[arg(long, short)]
     interpreter: Option<String>,
 
-    #[arg(long, short = 'x')]
+    #[arg(
+        long,
+        short = 'x',
+        allow_hyphen_values = true,
+        help = "Script to run, as a path relative to the project or the bare name of a detected executable, e.g. deploy. Python modules are given as \"-m package.module\", and \"npm start\" runs the start script of package.json."
+    )]
     entrypoint: Option<PathBuf>,
 
-    #[arg(long = "type", short = 't', value_enum)]
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Prompt for the entrypoint when several are detected and stdin is a terminal."
+    )]
+    #[serde(skip)]
+    select: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Also offer the scripts README.md shows being run in its code blocks, e.g. `python main.py` or `./run.sh`, as likely entrypoints. Heuristic, detected entrypoints still take part."
+    )]
+    #[serde(default)]
+    guess_from_readme: bool,
+
+    #[arg(long = "type", short = 'p', value_enum)]
     ptype: Option<envyr::package::PType>,
+
+    #[arg(
+        long,
+        help = "OS package to install in the sandbox. Merged with detected deps and kept in meta.json across regenerations. Allows multiples."
+    )]
+    #[serde(default)]
+    dep: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Skip OS dependency detection and drop previously saved --dep values, only using the --dep values given now."
+    )]
+    #[serde(default)]
+    no_auto_deps: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Keep an existing .envyr/Dockerfile instead of regenerating it, only meta.json is refreshed. A Dockerfile starting with '# envyr: keep' is always kept."
+    )]
+    #[serde(default)]
+    no_overwrite: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = envyr::meta::DockerignoreMode::Merge,
+        help = "How an existing .dockerignore at the project root is treated: merge in envyr's entries, overwrite it, or skip writing. One containing '# envyr-managed: false' is never written."
+    )]
+    #[serde(default)]
+    dockerignore_mode: envyr::meta::DockerignoreMode,
+
+    #[arg(
+        long = "pre-args",
+        allow_hyphen_values = true,
+        help = "Argument inserted between the interpreter and the entrypoint, e.g. --pre-args=-O. Allows multiples."
+    )]
+    #[serde(default)]
+    pre_args: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Directory holding the dependency manifests (requirements.txt, package.json, ...), relative to the project. Defaults to the project itself, then the closest parent up to the repository root."
+    )]
+    #[serde(default)]
+    deps_root: Option<PathBuf>,
+
+    #[arg(
+        long = "requirements",
+        help = "Python requirements file to install, relative to the dependency manifests directory, e.g. requirements-dev.txt. Allows multiples. Defaults to requirements.txt along with requirements-dev.txt or dev-requirements.txt if present."
+    )]
+    #[serde(default)]
+    requirements: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        value_parser = envyr::docker::parse_secret,
+        help = "Build secret mounted into the dependency install steps at /run/secrets/NAME, never stored in a layer. Format: id=NAME,src=PATH. Needs BuildKit. Allows multiples."
+    )]
+    #[serde(default)]
+    secret: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct K8sOpts {
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "registry",
+        conflicts_with = "stdout",
+        help = "Also print a Kubernetes Job manifest running the package's image, as pushed to --registry."
+    )]
+    k8s: bool,
+
+    #[arg(
+        long,
+        help = "Registry and namespace the image is pushed to, e.g. ghcr.io/me."
+    )]
+    registry: Option<String>,
+
+    #[arg(
+        long,
+        help = "Name of the image in the registry. Defaults to the package name."
+    )]
+    image_name: Option<String>,
+
+    #[arg(
+        long,
+        help = "With --k8s, environment variables for the job. Format: 'key=value' or 'key' (passthrough). Allows multiples."
+    )]
+    env_map: Vec<String>,
+
+    #[arg(
+        long,
+        help = "With --k8s, ports exposed by the job's container. Format host_port:container_port, only the container port is used. Allows multiples."
+    )]
+    port_map: Vec<String>,
+
+    #[arg(
+        long,
+        value_parser = envyr::docker::parse_memory,
+        help = "With --k8s, memory requested for the job, e.g. 512m or 2g."
+    )]
+    memory: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = envyr::docker::parse_cpus,
+        help = "With --k8s, CPUs requested for the job, e.g. 1.5."
+    )]
+    cpus: Option<f64>,
 }
 
 #[derive(Debug, Subcommand)]
 enum AliasSubcommand {
     #[clap(name = "list", about = "List all aliases.")]
-    List,
+    List {
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Print the aliases as JSON instead."
+        )]
+        json: bool,
+    },
 
     #[clap(name = "delete", about = "Delete an existing alias.")]
     Delete {
@@ -62,6 +218,8 @@ enum AliasSubcommand {
     },
 }
 
+// Parsed once per invocation, the size of the Run variant does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum Command {
     // Generate the meta.json file. This will overwrite if re-run.
@@ -76,55 +234,418 @@ enum Command {
         #[clap(flatten)]
         global_opts: GlobalOpts,
 
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Print the meta.json and Dockerfile that would be generated instead of writing any files."
+        )]
+        stdout: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["stdout", "k8s"],
+            help = "After writing the files, print the generated package (name, interpreter, entrypoint, ptype, deps, ...) as JSON."
+        )]
+        json: bool,
+
+        #[clap(flatten)]
+        k8s_opts: K8sOpts,
+
+        #[clap(flatten)]
+        args: OverrideOpts,
+
+        #[clap(raw = true, help = "With --k8s, arguments for the job's container.")]
+        job_args: Vec<String>,
+    },
+
+    #[clap(
+        name = "inspect",
+        about = "Show what envyr detects for a project, without generating or writing anything."
+    )]
+    Inspect {
+        #[clap(help = "The location to the project. Accepts, local filesystem path/git repos.")]
+        project_root: String,
+
+        #[clap(flatten)]
+        global_opts: GlobalOpts,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Print the report as JSON instead."
+        )]
+        json: bool,
+
         #[clap(flatten)]
         args: OverrideOpts,
     },
 
-    #[clap(name = "alias", about = "Subcommands for aliases.")]
-    Alias {
-        #[clap(subcommand)]
-        subcmd: AliasSubcommand,
-    },
+    #[clap(
+        name = "images",
+        visible_alias = "list",
+        about = "List the images built by envyr on this machine."
+    )]
+    Images {
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Print the images as JSON instead."
+        )]
+        json: bool,
+    },
+
+    #[clap(
+        name = "status",
+        about = "Show the last run of each alias/source, or the recent runs of one."
+    )]
+    Status {
+        #[clap(help = "The alias or source to show the recent runs of.")]
+        name: Option<String>,
+    },
+
+    #[clap(
+        name = "install",
+        about = "Build a package once and install a wrapper for it to ~/.envyr/bin."
+    )]
+    Install {
+        #[clap(
+            help = "The location to the project or an existing alias. Accepts, local filesystem path/git repos."
+        )]
+        project_root: String,
+
+        #[clap(
+            long = "as",
+            help = "Name of the installed command. Also recorded as an alias with this name."
+        )]
+        install_as: String,
+
+        #[clap(flatten)]
+        global_opts: GlobalOpts,
+
+        #[clap(long, short, value_enum, default_value_t = envyr::meta::Executors::Docker)]
+        executor: envyr::meta::Executors,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Attempt to automatically generate the package metadata before building. This overwrites existing metadata."
+        )]
+        autogen: bool,
+
+        #[clap(flatten)]
+        overrides: OverrideOpts,
+    },
+
+    #[clap(
+        name = "uninstall",
+        about = "Remove a wrapper installed with `envyr install` and its alias."
+    )]
+    Uninstall {
+        #[clap(help = "The name the package was installed as.")]
+        name: String,
+    },
+
+    #[clap(
+        name = "push",
+        about = "Build a package if needed and push its image to a registry."
+    )]
+    Push {
+        #[clap(
+            help = "The location to the project or an existing alias. Accepts, local filesystem path/git repos."
+        )]
+        project_root: String,
+
+        #[clap(
+            long,
+            help = "Registry and namespace to push to, e.g. ghcr.io/me. Log in with `docker login` first."
+        )]
+        registry: String,
+
+        #[clap(
+            long,
+            help = "Name of the image in the registry. Defaults to the package name."
+        )]
+        image_name: Option<String>,
+
+        #[clap(flatten)]
+        global_opts: GlobalOpts,
+
+        #[clap(long, short, value_enum, default_value_t = envyr::meta::Executors::Docker)]
+        executor: envyr::meta::Executors,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Attempt to automatically generate the package metadata before building. This overwrites existing metadata."
+        )]
+        autogen: bool,
+
+        #[clap(flatten)]
+        overrides: OverrideOpts,
+    },
+
+    #[clap(name = "alias", about = "Subcommands for aliases.")]
+    Alias {
+        #[clap(subcommand)]
+        subcmd: AliasSubcommand,
+    },
+
+    #[clap(name = "run", about = "Run the package with the given executor.")]
+    Run {
+        #[clap(
+            required = true,
+            help = "The location to the project. Accepts, local filesystem path/git repos. Several are run one after another, e.g. repos/*."
+        )]
+        project_root: Vec<String>,
+
+        #[clap(flatten)]
+        global_opts: GlobalOpts,
+
+        #[clap(
+            long,
+            help = "Upon successful completion, record this run command as an alias. To allow usage of `envyr run <alias>` in the future."
+        )]
+        alias: Option<String>,
+
+        #[clap(long, short, value_enum, default_value_t = envyr::meta::Executors::Docker)]
+        executor: envyr::meta::Executors,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Attempt to automatically generate the package metadata before running. This overwrites existing metadata."
+        )]
+        autogen: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "With --autogen, regenerate the package metadata even if the project is unchanged since the last generation."
+        )]
+        force_generate: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Always run the executor in interactive mode (allocate a tty). By default, a tty is allocated when stdin and stdout are both terminals."
+        )]
+        interactive: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            conflicts_with = "interactive",
+            help = "Never allocate a tty, even when stdin and stdout are terminals."
+        )]
+        no_interactive: bool,
+
+        #[clap(long, num_args = 0.., help ="Mount the given directory as a volume. Format: host_dir:container_dir[:mode], e.g. :ro. Allows multiples. Only applicable on Docker Executor.")]
+        fs_map: Vec<String>,
+
+        #[clap(long, num_args = 0.., help ="Map ports to host system, Format [host_ip:]host_port:source_port. Allows multiples. Only applicable on Docker Executor.")]
+        port_map: Vec<String>,
+
+        #[clap(long, num_args = 0.., help="Environment variables to pass through, leave value empty to pass through the value from the current environment. Format: 'key=value' or 'key' (passwthrough). Allows multiples.")]
+        env_map: Vec<String>,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Expand $VAR and ${VAR} in --env-map values from the other passed variables, then the host's. Write \\$ for a literal $. Values are kept literal without it. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        interpolate_env: bool,
+
+        #[clap(
+            long,
+            value_parser = envyr::docker::parse_add_host,
+            help = "Add a host entry to the container's /etc/hosts. Format: name:ip. Allows multiples. Only applicable on Docker Executor."
+        )]
+        add_host: Vec<String>,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Mount the current directory into the container and start there. Only applicable on Docker Executor."
+        )]
+        mount_cwd: bool,
+
+        #[clap(
+            long,
+            help = "Container directory to start in, without rebuilding the image. With --mount-cwd the current directory is mounted there, defaulting to /workspace. Only applicable on Docker Executor."
+        )]
+        workdir: Option<String>,
+
+        #[clap(
+            long,
+            allow_hyphen_values = true,
+            help = "Raw argument appended verbatim to the executor's run command, before the image name. Unvalidated and executor-specific, e.g. --runtime-flag=--privileged. Allows multiples. Only applicable on Docker Executor."
+        )]
+        runtime_flag: Vec<String>,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Rebuild the image without the executor's layer cache. Unlike --refresh, no cached layer (e.g. apk/pip installs) is reused. Only applicable on Docker Executor."
+        )]
+        no_cache: bool,
+
+        #[clap(
+            long,
+            help = "External layer cache to build from, passed to the executor's build as --cache-from, e.g. type=registry,ref=ghcr.io/me/cache. Needs BuildKit. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        cache_from: Option<String>,
+
+        #[clap(
+            long,
+            help = "External layer cache to export to, passed to the executor's build as --cache-to, e.g. type=registry,ref=ghcr.io/me/cache,mode=max. Needs BuildKit. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        cache_to: Option<String>,
+
+        #[clap(
+            long,
+            help = "Name for the container, e.g. to docker exec into it. Defaults to envyr-<name>-<tag>, with a numeric suffix if already in use. Only applicable on Docker Executor."
+        )]
+        container_name: Option<String>,
+
+        #[clap(
+            long,
+            value_name = "SECS",
+            help = "Give up after this many seconds, covering the fetch, build and run together. Partial clones are removed and the container is stopped. Kept with aliases."
+        )]
+        total_timeout: Option<u64>,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Print the time spent fetching (including metadata generation), building and running as JSON to stderr on completion."
+        )]
+        timings: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Keep the container after it exits instead of removing it, to inspect its logs and filesystem. Only applicable on Docker Executor."
+        )]
+        keep_container: bool,
+
+        #[clap(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["build_only", "shell"],
+            help = "Save the container's combined stdout/stderr to a file, still shown when stdout is a terminal. - writes both to stdout only. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        log_file: Option<String>,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Fetch, generate and build the image, then print its name and exit without running it. Only applicable on Docker Executor."
+        )]
+        build_only: bool,
+
+        #[clap(
+            long,
+            value_parser = envyr::docker::parse_copy_out,
+            help = "After the build, copy a path out of the image to the host, e.g. target/release/app:./app. Relative container paths are under /envyr/app. Format: container_path:host_path. Allows multiples. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        copy_out: Vec<String>,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            conflicts_with = "build_only",
+            help = "Start an interactive shell (bash if the image has it, else sh) in the built image instead of the entrypoint, for debugging. Only applicable on Docker Executor."
+        )]
+        shell: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["build_only", "shell", "snapshot"],
+            help = "Re-run whenever the local project changes, until interrupted. Files ignored by .dockerignore or .envyrignore are not watched. Only applicable on Docker Executor."
+        )]
+        watch: bool,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Rebuild the image, pulling a fresh base image first. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        pull: bool,
 
-    #[clap(name = "run", about = "Run the package with the given executor.")]
-    Run {
-        #[clap(help = "The location to the project. Accepts, local filesystem path/git repos.")]
-        project_root: String,
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Before building, check that the executor is logged in to the registries base images are pulled from, other than Docker Hub, e.g. a private base_image in envyr.toml. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        registry_auth: bool,
 
-        #[clap(flatten)]
-        global_opts: GlobalOpts,
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "After the build, check that the image has the program its entrypoint starts, e.g. the interpreter, and fail with a hint if not. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        verify_entrypoint: bool,
 
         #[clap(
             long,
-            help = "Upon successful completion, record this run command as an alias. To allow usage of `envyr run <alias>` in the future."
+            default_value_t = false,
+            help = "With podman, run with --userns=keep-id so files written to mounted volumes keep your ownership, and relabel volumes with :Z when SELinux is enabled. Kept with aliases. Ignored with docker."
         )]
-        alias: Option<String>,
+        podman_userns: bool,
 
-        #[clap(long, short, value_enum, default_value_t = envyr::meta::Executors::Docker)]
-        executor: envyr::meta::Executors,
+        #[clap(
+            long,
+            value_parser = envyr::docker::parse_memory,
+            help = "Memory limit for the container, e.g. 512m or 2g. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        memory: Option<String>,
+
+        #[clap(
+            long,
+            value_parser = envyr::docker::parse_cpus,
+            help = "Number of CPUs the container may use, e.g. 1.5. Kept with aliases. Only applicable on Docker Executor."
+        )]
+        cpus: Option<f64>,
 
         #[clap(
             long,
             default_value_t = false,
-            help = "Attempt to automatically generate the package metadata before running. This overwrites existing metadata."
+            conflicts_with = "allow_network",
+            help = "Run without network access (--network=none). Kept with aliases. Only applicable on Docker Executor."
         )]
-        autogen: bool,
+        no_network: bool,
 
         #[clap(
             long,
             default_value_t = false,
-            help = "Run the executor in interactive mode (allocate a tty)."
+            help = "Allow network access for fetched sources when isolate_remote is set in ~/.envyr/config.toml. Kept with aliases."
         )]
-        interactive: bool,
+        allow_network: bool,
 
-        #[clap(long, num_args = 0.., help ="Mount the given directory as a volume. Format: host_dir:container_dir. Allows multiples. Only applicable on Docker Executor.")]
-        fs_map: Vec<String>,
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "Copy a local project (minus its .dockerignore entries) to ~/.envyr/local and run the copy, so edits made meanwhile don't affect the run. Kept with aliases."
+        )]
+        snapshot: bool,
 
-        #[clap(long, num_args = 0.., help ="Map ports to host system, Format host_port:source_port. Allows multiples. Only applicable on Docker Executor.")]
-        port_map: Vec<String>,
+        #[clap(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["watch", "snapshot", "alias"],
+            help = "Read a single script from stdin and run it, with - as the source, e.g. `cat job.py | envyr run --stdin --type python -`. The script is named by --name or stdin.<ext> for --type."
+        )]
+        stdin: bool,
 
-        #[clap(long, num_args = 0.., help="Environment variables to pass through, leave value empty to pass through the value from the current environment. Format: 'key=value' or 'key' (passwthrough). Allows multiples.")]
-        env_map: Vec<String>,
+        #[clap(
+            long,
+            default_value_t = false,
+            requires = "stdin",
+            help = "With --stdin, keep the project written to ~/.envyr/stdin instead of removing it after the run."
+        )]
+        keep: bool,
 
         #[clap(flatten)]
         overrides: OverrideOpts,
@@ -146,22 +667,41 @@ pub struct App {
     #[arg(
         long,
         short,
-        help = "Emit Envyr logs to stdout. Useful for debugging. But may spoil pipes.",
+        action = clap::ArgAction::Count,
+        help = "Emit Envyr logs to stderr, leaving stdout to the package. Useful for debugging. -vv also traces."
+    )]
+    verbose: u8,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Print the commands that would be run instead of executing them.",
+        default_value_t = false
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        short,
+        global = true,
+        help = "Suppress informational output on stderr such as build progress and notices. Errors are still shown, --verbose wins.",
         default_value_t = false
     )]
-    verbose: bool,
+    quiet: bool,
 }
 
-fn setup_logging(verbose: bool) -> Result<()> {
-    let mut log_level = log::LevelFilter::Error;
-    if verbose {
-        log_level = log::LevelFilter::Debug;
-    }
+fn setup_logging(verbose: u8) -> Result<()> {
+    let log_level = match verbose {
+        0 => log::LevelFilter::Error,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
 
+    // Logs never go to stdout, so a package can still be used in a pipe
     simplelog::TermLogger::init(
         log_level,
         simplelog::Config::default(),
-        simplelog::TerminalMode::Mixed,
+        simplelog::TerminalMode::Stderr,
         simplelog::ColorChoice::Auto,
     )?;
     Ok(())
@@ -183,9 +723,21 @@ fn fetch(
     tag: &str,
     refresh: bool,
     subdir: Option<String>,
+    fetch_opts: &fetcher::FetchOpts,
+    snapshot: bool,
 ) -> Result<PathBuf> {
-    let p_fetcher = fetcher::get_fetcher(project_root, envyr_root)?;
+    let mut fetch_opts = fetch_opts.clone();
+    fetch_opts.fallback_branches = UserConfig::load(&envyr_root)?.fallback_branches;
+    let cache_root = cache_root(&envyr_root);
+    let p_fetcher = fetcher::get_fetcher(project_root, cache_root.clone(), fetch_opts)?;
     let mut path = p_fetcher.fetch(project_root, tag, refresh)?;
+    // Fetched sources are already copies of their own
+    if snapshot {
+        let source = std::fs::canonicalize(&path)?;
+        if !std::fs::canonicalize(&cache_root).is_ok_and(|root| source.starts_with(root)) {
+            path = envyr::adapters::local::snapshot(&source, &cache_root)?;
+        }
+    }
     if let Some(subdir) = subdir {
         path = path.join(subdir);
     }
@@ -193,7 +745,8 @@ fn fetch(
     Ok(path)
 }
 
-fn main() -> Result<()> {
+// Runs the command, returning the exit code to leave with.
+fn run_cli() -> Result<i32> {
     let start = Instant::now();
     let app = App::parse();
 
@@ -202,75 +755,370 @@ fn main() -> Result<()> {
     let envyr_root = homedir.join(".envyr");
 
     setup_logging(app.verbose)?;
+    let quiet = app.quiet && app.verbose == 0;
 
     match app.command {
         Command::Generate {
             args,
             project_root,
             global_opts,
+            stdout,
+            json,
+            k8s_opts,
+            job_args,
         } => {
+            let tag = global_opts.tag.unwrap_or("latest".to_string());
             let path = fetch(
                 envyr_root,
                 &project_root,
-                global_opts.tag.unwrap_or("latest".to_string()).as_str(),
+                tag.as_str(),
                 global_opts.refresh,
                 global_opts.sub_dir,
+                &global_opts.fetch_opts,
+                false,
             )?;
             debug!("Running Generator with args: {:?}", args);
-            generate(path, args)?;
+            generate(path.clone(), args, stdout, json)?;
+            if k8s_opts.k8s {
+                print!("{}", k8s_job(&path, &tag, k8s_opts, &job_args)?);
+            }
+        }
+        Command::Inspect {
+            project_root,
+            global_opts,
+            json,
+            args,
+        } => {
+            let path = fetch(
+                envyr_root,
+                &project_root,
+                global_opts.tag.as_deref().unwrap_or("latest"),
+                global_opts.refresh,
+                global_opts.sub_dir,
+                &global_opts.fetch_opts,
+                false,
+            )?;
+            let pack_builder = envyr::package::Pack::builder(&path)?;
+            let inspection = override_builder_opts(args, pack_builder).inspect();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspection)?);
+                return Ok(0);
+            }
+            print_inspection(&inspection);
         }
         Command::Run {
             project_root,
             global_opts,
             executor,
             interactive,
+            no_interactive,
             overrides,
             autogen,
+            force_generate,
             args,
             fs_map,
             env_map,
+            interpolate_env,
+            add_host,
             port_map,
+            runtime_flag,
+            no_cache,
+            cache_from,
+            cache_to,
+            pull,
+            registry_auth,
+            verify_entrypoint,
+            container_name,
+            keep_container,
+            log_file,
+            build_only,
+            copy_out,
+            shell,
+            watch,
+            timings,
+            total_timeout,
+            mount_cwd,
+            workdir,
+            podman_userns,
+            memory,
+            cpus,
+            no_network,
+            allow_network,
+            snapshot,
+            stdin,
+            keep,
             alias,
         } => {
             debug!(
                 "Running {:?} executor with autogen={}, fs_map:{:?}, port_map:{:?}, overrides:{:?} and args: {:?}",
                 executor, autogen, fs_map, port_map, overrides, args
             );
-            if let Some(mut config) = get_alias_config(envyr_root.clone(), project_root.clone()) {
-                debug!("Found alias config: {:?}", config);
-                if !args.is_empty() {
-                    config.args = args;
-                }
-                config.refresh = global_opts.refresh;
-                run(&envyr_root, config, start)?;
-                return Ok(()); // Early return if alias is found
+            let mut overrides = overrides;
+            let stdin_dir = match stdin {
+                true => Some(read_stdin_project(
+                    &envyr_root,
+                    &project_root,
+                    &mut overrides,
+                )?),
+                false => None,
             };
+            // The script is generated for like any other project
+            let autogen = autogen || stdin;
+            let sources = match &stdin_dir {
+                Some(dir) => vec![dir.display().to_string()],
+                None => project_root,
+            };
+            let project_root = sources[0].clone();
             let tag = global_opts.tag.unwrap_or("latest".to_string());
+            let literal_args = literal_args(&args);
+            let (interpreter_args, args) = split_interpreter_args(args);
             let config = RunConfig {
                 project_root,
                 executor,
                 interactive,
+                no_interactive,
                 refresh: global_opts.refresh,
                 autogen,
+                force_generate,
                 tag,
+                tag_from_git: global_opts.tag_from_git,
                 fs_map,
                 port_map,
                 sub_dir: global_opts.sub_dir,
+                fetch_opts: global_opts.fetch_opts,
                 env_map,
+                interpolate_env,
+                add_host,
+                copy_out,
+                runtime_flag,
+                no_cache,
+                cache_from,
+                cache_to,
+                pull,
+                registry_auth,
+                verify_entrypoint,
+                container_name,
+                keep_container,
+                log_file,
+                timings,
+                total_timeout,
+                mount_cwd,
+                workdir,
+                podman_userns,
+                memory,
+                cpus,
+                no_network,
+                allow_network,
+                snapshot,
                 overrides,
+                interpreter_args,
                 args,
+                literal_args,
+                dry_run: app.dry_run,
+                quiet,
+                build_only,
+                shell,
+                watch,
+                deadline: None,
+                revision: None,
             };
-            run(&envyr_root, config.clone(), start)?;
-            if let Some(alias) = alias {
+            if sources.len() > 1 {
+                if alias.is_some() || watch {
+                    return Err(anyhow::anyhow!(
+                        "--alias and --watch take a single source, got {}.",
+                        sources.len()
+                    ));
+                }
+                if let Some(name) = sources
+                    .iter()
+                    .find(|s| get_alias_config(envyr_root.clone(), s.to_string()).is_some())
+                {
+                    return Err(anyhow::anyhow!(
+                        "'{}' is an alias, aliases can only be run on their own.",
+                        name
+                    ));
+                }
+                return Ok(run_each(&envyr_root, &sources, config));
+            }
+            if let Some(mut alias_config) =
+                get_alias_config(envyr_root.clone(), config.project_root.clone())
+            {
+                debug!("Found alias config: {:?}", alias_config);
+                // Only for this invocation, the stored alias is left as is
+                alias_config.merge(&config);
+                // Early return if alias is found
+                return run(&envyr_root, &config.project_root, alias_config, start);
+            }
+            let result = run(&envyr_root, &config.project_root, config.clone(), start);
+            if let (Some(dir), false) = (stdin_dir, keep) {
+                std::fs::remove_dir_all(dir)?;
+            }
+            let code = result?;
+            if code != 0 {
+                return Ok(code);
+            }
+            if let (Some(alias), false) = (alias, app.dry_run) {
                 meta::store_alias(&envyr_root, alias, config)?;
             }
         }
+        Command::Install {
+            project_root,
+            install_as,
+            global_opts,
+            executor,
+            autogen,
+            overrides,
+        } => {
+            let mut config = build_config(
+                &envyr_root,
+                project_root,
+                &global_opts,
+                executor,
+                autogen,
+                overrides,
+            );
+            apply_build_opts(&mut config, global_opts, app.dry_run, quiet);
+            let (canon_path, config) = prepare(&envyr_root, config)?;
+            match config.executor {
+                envyr::meta::Executors::Docker => {
+                    envyr::docker::build(&canon_path, &config.run_options())?;
+                }
+                envyr::meta::Executors::Nix | envyr::meta::Executors::Native => {
+                    return Err(anyhow::anyhow!(
+                        "install is only supported with the docker/podman executor"
+                    ));
+                }
+            }
+            if app.dry_run {
+                return Ok(0);
+            }
+            let exe = std::env::current_exe()?;
+            let wrapper = envyr::install::install_wrapper(
+                &envyr::install::bin_dir(&envyr_root),
+                &install_as,
+                &exe,
+            )?;
+            meta::store_alias(&envyr_root, install_as, config)?;
+            println!("Installed {}", wrapper.display());
+        }
+        Command::Push {
+            project_root,
+            registry,
+            image_name,
+            global_opts,
+            executor,
+            autogen,
+            overrides,
+        } => {
+            let mut config = build_config(
+                &envyr_root,
+                project_root,
+                &global_opts,
+                executor,
+                autogen,
+                overrides,
+            );
+            apply_build_opts(&mut config, global_opts, app.dry_run, quiet);
+            let (canon_path, config) = prepare(&envyr_root, config)?;
+            match config.executor {
+                envyr::meta::Executors::Docker => {
+                    let remote = envyr::docker::push(
+                        &canon_path,
+                        &config.run_options(),
+                        &registry,
+                        image_name.as_deref(),
+                    )?;
+                    if !app.dry_run {
+                        println!("Pushed {}", remote);
+                    }
+                }
+                envyr::meta::Executors::Nix | envyr::meta::Executors::Native => {
+                    return Err(anyhow::anyhow!(
+                        "push is only supported with the docker/podman executor"
+                    ));
+                }
+            }
+        }
+        Command::Uninstall { name } => {
+            let wrapper =
+                envyr::install::uninstall_wrapper(&envyr::install::bin_dir(&envyr_root), &name)?;
+            meta::remove_alias(&envyr_root, name)?;
+            println!("Removed {}", wrapper.display());
+        }
+        Command::Status { name } => {
+            let runs = meta::load_runs(&envyr_root)?;
+            let rows = match &name {
+                Some(name) => match runs.get(name) {
+                    Some(history) => history.iter().rev().map(|r| (name, r)).collect(),
+                    None => vec![],
+                },
+                None => runs
+                    .iter()
+                    .filter_map(|(name, history)| Some((name, history.last()?)))
+                    .collect::<Vec<_>>(),
+            };
+            if rows.is_empty() {
+                println!("No runs recorded.");
+                return Ok(0);
+            }
+            let width = rows.iter().map(|(n, _)| n.len()).max().unwrap_or(0).max(4);
+            println!(
+                "{:width$}  {:19}  {:4}  TAG",
+                "NAME", "LAST RUN (UTC)", "EXIT"
+            );
+            for (name, record) in rows {
+                println!(
+                    "{:width$}  {:19}  {:4}  {}",
+                    name,
+                    envyr::utils::format_utc(record.timestamp),
+                    record.exit_code,
+                    record.tag
+                );
+            }
+        }
+        Command::Images { json } => {
+            let images = envyr::docker::list_images()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&images)?);
+                return Ok(0);
+            }
+            if images.is_empty() {
+                println!("No images found.");
+                return Ok(0);
+            }
+            let width = images.iter().map(|i| i.name.len()).max().unwrap_or(0);
+            println!("{:width$}  {:10}  {:10}  CREATED", "IMAGE", "TAG", "SIZE");
+            for image in images {
+                println!(
+                    "{:width$}  {:10}  {:10}  {}",
+                    image.name, image.tag, image.size, image.created
+                );
+            }
+        }
         Command::Alias { subcmd } => match subcmd {
-            AliasSubcommand::List => {
+            AliasSubcommand::List { json } => {
                 let aliases = meta::load_aliases(&envyr_root)?;
+                if json {
+                    let mut names = aliases.keys().collect::<Vec<&String>>();
+                    names.sort();
+                    let entries = names
+                        .into_iter()
+                        .map(|name| {
+                            let config = &aliases[name];
+                            serde_json::json!({
+                                "name": name,
+                                "project_root": config.project_root,
+                                "executor": config.executor,
+                                "tag": config.tag,
+                            })
+                        })
+                        .collect::<Vec<serde_json::Value>>();
+                    println!("{}", serde_json::to_string_pretty(&entries)?);
+                    return Ok(0);
+                }
                 if aliases.is_empty() {
                     println!("No aliases found.");
-                    return Ok(());
+                    return Ok(0);
                 }
                 for (alias, config) in aliases {
                     println!("{}: {:?}", alias, config.project_root);
@@ -282,69 +1130,652 @@ fn main() -> Result<()> {
         },
     }
 
-    Ok(())
+    Ok(0)
+}
+
+fn main() -> ExitCode {
+    match run_cli() {
+        // Exit codes are truncated to a byte, as with std::process::exit
+        Ok(code) => ExitCode::from(code as u8),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct RunConfig {
     project_root: String,
     sub_dir: Option<String>,
+    #[serde(default)]
+    fetch_opts: fetcher::FetchOpts,
     executor: envyr::meta::Executors,
     interactive: bool,
+    #[serde(default)]
+    no_interactive: bool,
     refresh: bool,
     autogen: bool,
+    #[serde(default)]
+    force_generate: bool,
     tag: String,
+    #[serde(default)]
+    tag_from_git: bool,
     fs_map: Vec<String>,
     port_map: Vec<String>,
     env_map: Vec<String>,
+    #[serde(default)]
+    interpolate_env: bool,
+    #[serde(default)]
+    add_host: Vec<String>,
+    #[serde(default)]
+    copy_out: Vec<String>,
+    #[serde(default)]
+    runtime_flag: Vec<String>,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    cache_from: Option<String>,
+    #[serde(default)]
+    cache_to: Option<String>,
+    #[serde(default)]
+    pull: bool,
+    #[serde(default)]
+    registry_auth: bool,
+    #[serde(default)]
+    verify_entrypoint: bool,
+    #[serde(default)]
+    container_name: Option<String>,
+    #[serde(default)]
+    keep_container: bool,
+    #[serde(default)]
+    log_file: Option<String>,
+    #[serde(default)]
+    timings: bool,
+    #[serde(default)]
+    total_timeout: Option<u64>,
+    #[serde(default)]
+    mount_cwd: bool,
+    #[serde(default)]
+    workdir: Option<String>,
+    #[serde(default)]
+    podman_userns: bool,
+    #[serde(default)]
+    memory: Option<String>,
+    #[serde(default)]
+    cpus: Option<f64>,
+    #[serde(default)]
+    no_network: bool,
+    #[serde(default)]
+    allow_network: bool,
+    #[serde(default)]
+    snapshot: bool,
     overrides: OverrideOpts,
+    #[serde(default)]
+    interpreter_args: Vec<String>,
     args: Vec<String>,
+    #[serde(default)]
+    literal_args: bool,
+    #[serde(skip)]
+    dry_run: bool,
+    #[serde(skip)]
+    quiet: bool,
+    #[serde(skip)]
+    build_only: bool,
+    #[serde(skip)]
+    shell: bool,
+    #[serde(skip)]
+    watch: bool,
+    // From --total-timeout, counted from the start of the run.
+    #[serde(skip)]
+    deadline: Option<Instant>,
+    // Commit resolved for --tag-from-git once fetched.
+    #[serde(skip)]
+    revision: Option<String>,
+}
+
+impl RunConfig {
+    fn image_tag(&self) -> String {
+        envyr::docker::image_tag(&self.tag, self.revision.as_deref())
+    }
+
+    // Applies the options given on the command line when running an alias.
+    // Flags only switch on, maps merge by key, given values replace stored
+    // ones and the per-invocation options are taken as they are.
+    fn merge(&mut self, cli: &RunConfig) {
+        if !cli.args.is_empty() {
+            self.interpreter_args = cli.interpreter_args.clone();
+            self.args = cli.args.clone();
+            self.literal_args = cli.literal_args;
+        }
+        if cli.interactive || cli.no_interactive {
+            self.interactive = cli.interactive;
+            self.no_interactive = cli.no_interactive;
+        }
+
+        self.tag_from_git |= cli.tag_from_git;
+        self.pull |= cli.pull;
+        self.registry_auth |= cli.registry_auth;
+        self.verify_entrypoint |= cli.verify_entrypoint;
+        self.keep_container |= cli.keep_container;
+        self.timings |= cli.timings;
+        self.podman_userns |= cli.podman_userns;
+        self.no_network |= cli.no_network;
+        self.allow_network |= cli.allow_network;
+        self.snapshot |= cli.snapshot;
+        self.interpolate_env |= cli.interpolate_env;
+        self.mount_cwd |= cli.mount_cwd;
+
+        self.env_map = merge_maps(&self.env_map, &cli.env_map, env_map_key);
+        self.port_map = merge_maps(&self.port_map, &cli.port_map, port_map_key);
+        self.fs_map = merge_maps(&self.fs_map, &cli.fs_map, fs_map_key);
+        self.add_host = merge_maps(&self.add_host, &cli.add_host, add_host_key);
+        self.copy_out = merge_maps(&self.copy_out, &cli.copy_out, copy_out_key);
+        self.runtime_flag.extend(cli.runtime_flag.iter().cloned());
+
+        fn replace<T: Clone>(stored: &mut Option<T>, given: &Option<T>) {
+            if given.is_some() {
+                stored.clone_from(given);
+            }
+        }
+        replace(&mut self.container_name, &cli.container_name);
+        replace(&mut self.log_file, &cli.log_file);
+        replace(&mut self.memory, &cli.memory);
+        replace(&mut self.cpus, &cli.cpus);
+        replace(&mut self.cache_from, &cli.cache_from);
+        replace(&mut self.cache_to, &cli.cache_to);
+        replace(&mut self.total_timeout, &cli.total_timeout);
+        replace(&mut self.workdir, &cli.workdir);
+        replace(
+            &mut self.fetch_opts.git_ssh_key,
+            &cli.fetch_opts.git_ssh_key,
+        );
+        replace(
+            &mut self.fetch_opts.default_branch,
+            &cli.fetch_opts.default_branch,
+        );
+
+        // Tokens and proxies are never stored with aliases
+        self.fetch_opts.git_token = cli.fetch_opts.git_token.clone();
+        self.fetch_opts.proxy = cli.fetch_opts.proxy.clone();
+        self.refresh = cli.refresh;
+        self.no_cache = cli.no_cache;
+        self.force_generate = cli.force_generate;
+        self.dry_run = cli.dry_run;
+        self.quiet = cli.quiet;
+        self.build_only = cli.build_only;
+        self.shell = cli.shell;
+        self.watch = cli.watch;
+    }
+
+    // The options the docker executor builds and runs with.
+    fn run_options(&self) -> envyr::docker::RunOptions {
+        envyr::docker::RunOptions {
+            tag: self.tag.clone(),
+            revision: self.revision.clone(),
+            refresh: self.refresh,
+            no_cache: self.no_cache,
+            pull: self.pull,
+            cache_from: self.cache_from.clone(),
+            cache_to: self.cache_to.clone(),
+            secrets: self.overrides.secret.clone(),
+            registry_auth: self.registry_auth,
+            verify_entrypoint: self.verify_entrypoint,
+            copy_out: self.copy_out.clone(),
+            interactive: self.interactive,
+            no_interactive: self.no_interactive,
+            fs_map: self.fs_map.clone(),
+            port_map: self.port_map.clone(),
+            env_map: self.env_map.clone(),
+            interpolate_env: self.interpolate_env,
+            add_host: self.add_host.clone(),
+            runtime_flag: self.runtime_flag.clone(),
+            container_name: self.container_name.clone(),
+            keep_container: self.keep_container,
+            log_file: self.log_file.clone(),
+            mount_cwd: self.mount_cwd,
+            workdir: self.workdir.clone(),
+            podman_userns: self.podman_userns,
+            memory: self.memory.clone(),
+            cpus: self.cpus,
+            no_network: self.no_network,
+            interpreter_args: self.interpreter_args.clone(),
+            args: self.args.clone(),
+            literal_args: self.literal_args,
+            deadline: self.deadline,
+            dry_run: self.dry_run,
+            quiet: self.quiet,
+            shell: self.shell,
+        }
+    }
+}
+
+// The config for building a package: the alias of this name if there is
+// one, otherwise the given options with every run option left at its default.
+fn build_config(
+    envyr_root: &Path,
+    project_root: String,
+    global_opts: &GlobalOpts,
+    executor: envyr::meta::Executors,
+    autogen: bool,
+    overrides: OverrideOpts,
+) -> RunConfig {
+    if let Some(config) = get_alias_config(envyr_root.to_path_buf(), project_root.clone()) {
+        return config;
+    }
+    RunConfig {
+        project_root,
+        sub_dir: global_opts.sub_dir.clone(),
+        fetch_opts: global_opts.fetch_opts.clone(),
+        executor,
+        interactive: false,
+        no_interactive: false,
+        refresh: false,
+        autogen,
+        force_generate: false,
+        tag: global_opts.tag.clone().unwrap_or("latest".to_string()),
+        tag_from_git: global_opts.tag_from_git,
+        fs_map: vec![],
+        port_map: vec![],
+        env_map: vec![],
+        interpolate_env: false,
+        add_host: vec![],
+        copy_out: vec![],
+        runtime_flag: vec![],
+        no_cache: false,
+        cache_from: None,
+        cache_to: None,
+        pull: false,
+        registry_auth: false,
+        verify_entrypoint: false,
+        container_name: None,
+        keep_container: false,
+        log_file: None,
+        timings: false,
+        total_timeout: None,
+        mount_cwd: false,
+        workdir: None,
+        podman_userns: false,
+        memory: None,
+        cpus: None,
+        no_network: false,
+        allow_network: false,
+        snapshot: false,
+        overrides,
+        interpreter_args: vec![],
+        args: vec![],
+        literal_args: false,
+        dry_run: false,
+        quiet: false,
+        build_only: false,
+        shell: false,
+        watch: false,
+        deadline: None,
+        revision: None,
+    }
+}
+
+// Applies the options given on this invocation over a build config, even
+// when it came from an alias.
+fn apply_build_opts(config: &mut RunConfig, global_opts: GlobalOpts, dry_run: bool, quiet: bool) {
+    config.refresh = global_opts.refresh;
+    config.tag_from_git |= global_opts.tag_from_git;
+    config.fetch_opts.git_token = global_opts.fetch_opts.git_token;
+    config.fetch_opts.proxy = global_opts.fetch_opts.proxy;
+    config.dry_run = dry_run;
+    config.quiet = quiet;
+}
+
+// Splits the raw run args on a second `--` into interpreter args and script
+// args. Without one, all of them go to the script.
+fn split_interpreter_args(mut args: Vec<String>) -> (Vec<String>, Vec<String>) {
+    match args.iter().position(|a| a == "--") {
+        Some(i) => {
+            let script_args = args.split_off(i + 1);
+            args.pop();
+            (args, script_args)
+        }
+        None => (vec![], args),
+    }
+}
+
+// Args after a leading second `--` go to the default entrypoint as they are,
+// even when the first one names a command.
+fn literal_args(args: &[String]) -> bool {
+    args.first().is_some_and(|a| a == "--")
 }
 
-fn run(envyr_root: &Path, config: RunConfig, start: Instant) -> Result<()> {
+// Runs the package, then again whenever the project changes, until interrupted.
+// Sources of interpreted packages are mounted over the image's copy, so only
+// manifest changes rebuild the image. Other packages are rebuilt every time.
+fn watch(envyr_root: &Path, name: &str, mut config: RunConfig) -> Result<i32> {
+    config.watch = false;
+    let received = envyr::utils::register_interrupts()?;
     let canon_path = fetch(
         envyr_root.to_path_buf(),
         &config.project_root,
         config.tag.as_str(),
-        config.refresh,
-        config.sub_dir,
+        false,
+        config.sub_dir.clone(),
+        &config.fetch_opts,
+        false,
     )?;
-    if config.autogen {
-        let pack_builder = envyr::package::Pack::builder(&canon_path)?;
-        let pack_builder = override_builder_opts(config.overrides, pack_builder);
-        let pack = pack_builder.build()?;
-        let generator = envyr::meta::Generator::new(pack);
-        generator.generate(&canon_path)?;
+    if std::fs::canonicalize(cache_root(envyr_root)).is_ok_and(|root| canon_path.starts_with(root))
+    {
+        return Err(anyhow::anyhow!(
+            "--watch needs a local project, {} is fetched into envyr's cache.",
+            config.project_root
+        ));
+    }
+    let watcher = envyr::watch::ProjectWatcher::new(&canon_path)?;
+    let mut code = run(envyr_root, name, config.clone(), Instant::now())?;
+    let mountable = envyr::package::Pack::load(&canon_path).is_ok_and(|pack| {
+        matches!(
+            pack.ptype,
+            envyr::package::PType::Python
+                | envyr::package::PType::Shell
+                | envyr::package::PType::Perl
+                | envyr::package::PType::Other
+        )
+    });
+    if mountable {
+        config
+            .fs_map
+            .push(format!("{}:/envyr/app", canon_path.display()));
+    }
+    loop {
+        if !config.quiet {
+            eprintln!(
+                "Watching {} for changes, Ctrl-C to stop.",
+                canon_path.display()
+            );
+        }
+        let changed = match watcher.wait(&received)? {
+            Some(changed) => changed,
+            None => return Ok(code),
+        };
+        let mut config = config.clone();
+        config.refresh = !mountable || changed.iter().any(|p| meta::is_manifest(p));
+        code = match run(envyr_root, name, config, Instant::now()) {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                1
+            }
+        };
+    }
+}
+
+// Writes the script piped with --stdin to a project and makes it the entrypoint.
+fn read_stdin_project(
+    envyr_root: &Path,
+    sources: &[String],
+    overrides: &mut OverrideOpts,
+) -> Result<PathBuf> {
+    if sources != ["-"] {
+        return Err(anyhow::anyhow!(
+            "--stdin reads the script from stdin, pass - as the only source."
+        ));
+    }
+    if std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "--stdin expects a script piped in, e.g. `cat job.py | envyr run --stdin --type python -`."
+        ));
+    }
+    let file_name = envyr::adapters::local::stdin_file_name(
+        overrides.name.as_deref(),
+        overrides.ptype.as_ref(),
+    )?;
+    let mut script = vec![];
+    std::io::stdin().read_to_end(&mut script)?;
+    let dir = envyr::adapters::local::stdin_project(&cache_root(envyr_root), &file_name, &script)?;
+    overrides.entrypoint = Some(PathBuf::from(file_name));
+    Ok(dir)
+}
+
+// Runs the same config against each source in turn and prints a summary.
+// Returns 1 if any of them failed.
+fn run_each(envyr_root: &Path, sources: &[String], config: RunConfig) -> i32 {
+    let results = sources
+        .iter()
+        .map(|source| {
+            let mut config = config.clone();
+            config.project_root = source.clone();
+            let result = run(envyr_root, source, config, Instant::now());
+            (source, result)
+        })
+        .collect::<Vec<_>>();
+    let failed = results.iter().filter(|(_, r)| !matches!(r, Ok(0))).count();
+    eprintln!("Ran {} sources, {} failed:", results.len(), failed);
+    for (source, result) in results {
+        match result {
+            Ok(0) => eprintln!("  ok       {}", source),
+            Ok(code) => eprintln!("  exit {:<3} {}", code, source),
+            Err(e) => eprintln!("  error    {}: {}", source, e),
+        }
+    }
+    i32::from(failed > 0)
+}
+
+// Runs the package and returns the exit code of the executed program.
+// Runs are recorded under `name`, the alias or source given.
+fn run(envyr_root: &Path, name: &str, mut config: RunConfig, start: Instant) -> Result<i32> {
+    if config.watch && !config.dry_run {
+        return watch(envyr_root, name, config);
     }
-    match config.executor {
+    config.deadline = config
+        .total_timeout
+        .map(|secs| start + std::time::Duration::from_secs(secs));
+    config.fetch_opts.deadline = config.deadline;
+    let fetch_start = Instant::now();
+    let (canon_path, config) = prepare(envyr_root, config)?;
+    let mut timings = envyr::utils::Timings {
+        fetch_ms: fetch_start.elapsed().as_millis(),
+        ..Default::default()
+    };
+    let code = match config.executor {
+        envyr::meta::Executors::Docker if config.build_only => {
+            let options = config.run_options();
+            let image = envyr::docker::build(&canon_path, &options)?;
+            envyr::docker::copy_out(&image, &options)?;
+            envyr::docker::verify_entrypoint(&image, &options)?;
+            println!("{}", image);
+            0
+        }
         envyr::meta::Executors::Docker => {
-            envyr::docker::run(
-                &canon_path,
-                config.refresh,
-                config.interactive,
-                config.tag,
-                config.fs_map,
-                config.port_map,
-                config.env_map,
-                config.args,
-                start,
-            )?;
+            envyr::docker::run(&canon_path, &config.run_options(), start, &mut timings)?
         }
         envyr::meta::Executors::Nix => todo!(),
         envyr::meta::Executors::Native => todo!(),
+    };
+    if config.timings {
+        eprintln!("{}", serde_json::to_string(&timings)?);
     }
-    Ok(())
+    if !config.dry_run && !config.build_only && !config.shell {
+        let record = meta::RunRecord {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            exit_code: code,
+            tag: config.image_tag(),
+        };
+        if let Err(e) = meta::record_run(envyr_root, name.to_string(), record) {
+            debug!("Failed to record the run: {}", e);
+        }
+    }
+    Ok(code)
+}
+
+// Fetches the package and resolves the final config, generating metadata if asked to.
+fn prepare(envyr_root: &Path, mut config: RunConfig) -> Result<(PathBuf, RunConfig)> {
+    envyr::docker::validate_maps(&config.fs_map, &config.port_map, &config.env_map)?;
+    let canon_path = fetch(
+        envyr_root.to_path_buf(),
+        &config.project_root,
+        config.tag.as_str(),
+        config.refresh,
+        config.sub_dir.clone(),
+        &config.fetch_opts,
+        config.snapshot,
+    )?;
+    if config.tag_from_git {
+        let revision = envyr::adapters::git::head_revision(&canon_path)?;
+        debug!("Tagging the image with commit {}", revision);
+        config.revision = Some(revision);
+    }
+
+    // Fetched sources are untrusted, users can opt into isolating them by default
+    // (--snapshot copies of local projects are not)
+    let fetched = std::fs::canonicalize(cache_root(envyr_root)).is_ok_and(|root| {
+        canon_path.starts_with(&root) && !canon_path.starts_with(root.join("local"))
+    });
+    if fetched
+        && !config.no_network
+        && !config.allow_network
+        && UserConfig::load(envyr_root)?.isolate_remote
+    {
+        if !config.quiet {
+            eprintln!("Running fetched source without network access (isolate_remote), pass --allow-network to allow it.");
+        }
+        config.no_network = true;
+    }
+
+    // Defaults from envyr.toml, explicit flags win
+    let project_config = ProjectConfig::load(&canon_path)?;
+    envyr::docker::validate_maps(
+        &project_config.fs_map,
+        &project_config.port_map,
+        &project_config.env_map,
+    )?;
+    config.env_map = merge_maps(&project_config.env_map, &config.env_map, env_map_key);
+    config.fs_map = merge_maps(&project_config.fs_map, &config.fs_map, fs_map_key);
+    config.port_map = merge_maps(&project_config.port_map, &config.port_map, port_map_key);
+    if config.autogen {
+        let pack_builder = envyr::package::Pack::builder(&canon_path)?;
+        let pack_builder = override_builder_opts(config.overrides.clone(), pack_builder);
+        let pack = pack_builder.build()?;
+        let generator = envyr::meta::Generator::new(pack)
+            .no_overwrite(config.overrides.no_overwrite)
+            .dockerignore_mode(config.overrides.dockerignore_mode.clone())
+            .force(config.force_generate);
+        generator.generate(&canon_path)?;
+    }
+    Ok((canon_path, config))
 }
 
-fn generate(canon_path: PathBuf, args: OverrideOpts) -> Result<()> {
+fn generate(canon_path: PathBuf, args: OverrideOpts, stdout: bool, json: bool) -> Result<()> {
+    let no_overwrite = args.no_overwrite;
+    let dockerignore_mode = args.dockerignore_mode.clone();
     let pack_builder = envyr::package::Pack::builder(&canon_path)?;
     let pack_builder = override_builder_opts(args, pack_builder);
     let pack = pack_builder.build()?;
-    let generator = envyr::meta::Generator::new(pack);
+    let generator = envyr::meta::Generator::new(pack)
+        .no_overwrite(no_overwrite)
+        .dockerignore_mode(dockerignore_mode)
+        .force(true);
+    if stdout {
+        print!("{}", generator.preview(&canon_path)?);
+        return Ok(());
+    }
     generator.generate(&canon_path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&generator.pack)?);
+    }
     Ok(())
 }
 
+fn print_inspection(inspection: &envyr::package::Inspection) {
+    let unset = |value: Option<String>| value.unwrap_or("-".to_string());
+    let paths = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    println!("Project:     {}", inspection.project_root.display());
+    println!("Name:        {}", unset(inspection.name.clone()));
+    match &inspection.detection {
+        Some(detection) => println!(
+            "Type:        {:?} (confidence {}, from {})",
+            inspection.ptype,
+            detection.confidence,
+            paths(&detection.marker_files)
+        ),
+        None => println!("Type:        {:?}", inspection.ptype),
+    }
+    println!("Interpreter: {}", unset(inspection.interpreter.clone()));
+    println!(
+        "Entrypoint:  {}",
+        unset(
+            inspection
+                .entrypoint
+                .as_ref()
+                .map(|e| e.display().to_string())
+        )
+    );
+    println!(
+        "Deps root:   {}",
+        unset(
+            inspection
+                .deps_root
+                .as_ref()
+                .map(|d| d.display().to_string())
+        )
+    );
+    println!(
+        "Deps:        {}",
+        unset(Some(inspection.deps.join(", ")).filter(|d| !d.is_empty()))
+    );
+    for (name, entrypoint) in &inspection.commands {
+        println!("Command:     {} -> {}", name, entrypoint.display());
+    }
+    if inspection.candidates.is_empty() {
+        println!("No entrypoint candidates found.");
+        return;
+    }
+    println!("\nCandidates, the lowest priority is preferred:");
+    let width = inspection
+        .candidates
+        .iter()
+        .map(|c| c.path.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(10);
+    println!("{:8}  {:width$}  INTERPRETER", "PRIORITY", "ENTRYPOINT");
+    for candidate in &inspection.candidates {
+        println!(
+            "{:<8}  {:width$}  {}",
+            candidate.priority,
+            candidate.path.display(),
+            candidate.interpreter
+        );
+    }
+}
+
+// Renders the Job manifest for a generated package, with the maps from
+// envyr.toml merged in.
+fn k8s_job(canon_path: &Path, tag: &str, opts: K8sOpts, args: &[String]) -> Result<String> {
+    let pack = envyr::package::Pack::load(canon_path)?;
+    let project_config = ProjectConfig::load(canon_path)?;
+    envyr::docker::validate_maps(&[], &opts.port_map, &opts.env_map)?;
+    let env_map = merge_maps(&project_config.env_map, &opts.env_map, env_map_key);
+    let port_map = merge_maps(&project_config.port_map, &opts.port_map, port_map_key);
+    let image = envyr::docker::get_pushable_image_name(
+        &opts.registry.unwrap_or_default(),
+        opts.image_name.as_deref().unwrap_or(&pack.name),
+        tag,
+    );
+    envyr::k8s::generate_k8s_job(
+        &pack,
+        &image,
+        args,
+        &env_map,
+        &port_map,
+        (opts.memory.as_deref(), opts.cpus),
+    )
+}
+
 fn override_builder_opts(
     args: OverrideOpts,
     mut pack_builder: envyr::package::PackBuilder,
@@ -365,5 +1796,157 @@ fn override_builder_opts(
     if let Some(ptype) = args.ptype {
         pack_builder = pack_builder.ptype(ptype);
     }
+
+    pack_builder = pack_builder.select(args.select);
+
+    if args.guess_from_readme {
+        pack_builder = pack_builder.guess_from_readme();
+    }
+
+    if !args.pre_args.is_empty() {
+        pack_builder = pack_builder.pre_args(args.pre_args);
+    }
+
+    if let Some(deps_root) = args.deps_root {
+        pack_builder = pack_builder.deps_root(deps_root);
+    }
+
+    if !args.requirements.is_empty() {
+        pack_builder = pack_builder.requirements(args.requirements);
+    }
+
+    pack_builder = pack_builder.secrets(
+        args.secret
+            .iter()
+            .map(|s| envyr::docker::secret_id(s).to_string())
+            .collect(),
+    );
+
+    if args.no_auto_deps {
+        pack_builder = pack_builder.no_auto_deps(args.dep);
+    } else {
+        pack_builder = pack_builder.deps(args.dep);
+    }
     pack_builder
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        App::command().debug_assert();
+    }
+
+    #[test]
+    fn test_split_interpreter_args() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            split_interpreter_args(args(&["-O", "--", "--flag", "--", "x"])),
+            (args(&["-O"]), args(&["--flag", "--", "x"]))
+        );
+        assert_eq!(
+            split_interpreter_args(args(&["--flag", "x"])),
+            (vec![], args(&["--flag", "x"]))
+        );
+        assert!(literal_args(&args(&["--", "worker"])));
+        assert!(!literal_args(&args(&["-O", "--", "worker"])));
+        assert!(!literal_args(&args(&["worker"])));
+    }
+
+    fn strings(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_merge_flags() {
+        let mut alias = RunConfig {
+            pull: true,
+            no_cache: true,
+            ..Default::default()
+        };
+        alias.merge(&RunConfig {
+            mount_cwd: true,
+            ..Default::default()
+        });
+        // Stored flags stay on, per-invocation ones are taken as given
+        assert!(alias.pull);
+        assert!(alias.mount_cwd);
+        assert!(!alias.no_cache);
+
+        alias.interactive = true;
+        alias.merge(&RunConfig::default());
+        assert!(alias.interactive);
+        alias.merge(&RunConfig {
+            no_interactive: true,
+            ..Default::default()
+        });
+        assert!(!alias.interactive);
+        assert!(alias.no_interactive);
+    }
+
+    #[test]
+    fn test_merge_options() {
+        let mut alias = RunConfig {
+            memory: Some("512m".to_string()),
+            cpus: Some(1.0),
+            workdir: Some("/src".to_string()),
+            ..Default::default()
+        };
+        alias.fetch_opts.git_token = Some("stale".to_string());
+        let mut cli = RunConfig {
+            memory: Some("1g".to_string()),
+            ..Default::default()
+        };
+        cli.fetch_opts.default_branch = Some("dev".to_string());
+        alias.merge(&cli);
+        assert_eq!(alias.memory.as_deref(), Some("1g"));
+        assert_eq!(alias.cpus, Some(1.0));
+        assert_eq!(alias.workdir.as_deref(), Some("/src"));
+        assert_eq!(alias.fetch_opts.default_branch.as_deref(), Some("dev"));
+        // Never kept from the alias
+        assert_eq!(alias.fetch_opts.git_token, None);
+    }
+
+    #[test]
+    fn test_merge_maps() {
+        let mut alias = RunConfig {
+            env_map: strings(&["A=1", "B=2"]),
+            port_map: strings(&["8080:80"]),
+            runtime_flag: strings(&["--init"]),
+            ..Default::default()
+        };
+        alias.merge(&RunConfig {
+            env_map: strings(&["B=3"]),
+            port_map: strings(&["9090:90"]),
+            runtime_flag: strings(&["--privileged"]),
+            ..Default::default()
+        });
+        assert_eq!(alias.env_map, strings(&["A=1", "B=3"]));
+        assert_eq!(alias.port_map, strings(&["8080:80", "9090:90"]));
+        assert_eq!(alias.runtime_flag, strings(&["--init", "--privileged"]));
+    }
+
+    #[test]
+    fn test_merge_args() {
+        let mut alias = RunConfig {
+            interpreter_args: strings(&["-O"]),
+            args: strings(&["--verbose"]),
+            ..Default::default()
+        };
+        alias.merge(&RunConfig::default());
+        assert_eq!(alias.interpreter_args, strings(&["-O"]));
+        assert_eq!(alias.args, strings(&["--verbose"]));
+
+        alias.merge(&RunConfig {
+            args: strings(&["worker"]),
+            literal_args: true,
+            ..Default::default()
+        });
+        assert!(alias.interpreter_args.is_empty());
+        assert_eq!(alias.args, strings(&["worker"]));
+        assert!(alias.literal_args);
+    }
+}
@@ -0,0 +1,132 @@
+// End-to-end tests that generate, build and run a real container.
+// They need a working docker/podman daemon, so they're ignored by default.
+// Run with: cargo test -- --ignored
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn runtime_available() -> bool {
+    ["docker", "podman"].iter().any(|bin| {
+        Command::new(bin)
+            .arg("info")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    })
+}
+
+macro_rules! require_runtime {
+    () => {
+        if !runtime_available() {
+            eprintln!("No container runtime available, skipping.");
+            return;
+        }
+    };
+}
+
+// Creates a trivial python pack. The empty requirements.txt keeps autogen from
+// shelling out to pipreqs.
+fn python_pack(code: &str) -> tempfile::TempDir {
+    let dir = tempfile::Builder::new()
+        .prefix("envyr-e2e")
+        .tempdir()
+        .unwrap();
+    std::fs::write(dir.path().join("main.py"), code).unwrap();
+    std::fs::write(dir.path().join("requirements.txt"), "").unwrap();
+    dir
+}
+
+fn envyr_run(project: &Path, opts: &[&str], args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_envyr"))
+        .arg("run")
+        .arg("--autogen")
+        .args(opts)
+        .arg(project)
+        .arg("--")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+#[ignore]
+fn test_run_python_pack() {
+    require_runtime!();
+    let pack = python_pack(
+        r#"
+import sys
+
+if __name__ == "__main__":
+    print("hello from envyr", sys.argv[1])
+"#,
+    );
+    let out = envyr_run(pack.path(), &[], &["world"]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout).trim(),
+        "hello from envyr world"
+    );
+}
+
+#[test]
+#[ignore]
+fn test_run_propagates_failure() {
+    require_runtime!();
+    let pack = python_pack(
+        r#"
+import sys
+
+if __name__ == "__main__":
+    sys.exit(3)
+"#,
+    );
+    let out = envyr_run(pack.path(), &[], &[]);
+    assert_eq!(out.status.code(), Some(3), "{:?}", out);
+}
+
+#[test]
+#[ignore]
+fn test_fs_map_writes_to_host() {
+    require_runtime!();
+    let pack = python_pack(
+        r#"
+if __name__ == "__main__":
+    with open("/out/result.txt", "w") as f:
+        f.write("written by envyr")
+"#,
+    );
+    let host_dir = tempfile::tempdir().unwrap();
+    let fs_map = format!("--fs-map={}:/out", host_dir.path().display());
+    let out = envyr_run(pack.path(), &[&fs_map], &[]);
+    assert!(out.status.success(), "{:?}", out);
+    let written = std::fs::read_to_string(host_dir.path().join("result.txt")).unwrap();
+    assert_eq!(written, "written by envyr");
+}
+
+#[test]
+#[ignore]
+fn test_env_map_passes_values() {
+    require_runtime!();
+    let pack = python_pack(
+        r#"
+import os
+
+if __name__ == "__main__":
+    print(os.environ["ENVYR_E2E_SET"], os.environ["ENVYR_E2E_PASS"])
+"#,
+    );
+    let out = Command::new(env!("CARGO_BIN_EXE_envyr"))
+        .env("ENVYR_E2E_PASS", "inherited")
+        .arg("run")
+        .arg("--autogen")
+        .arg("--env-map=ENVYR_E2E_SET=explicit")
+        .arg("--env-map=ENVYR_E2E_PASS")
+        .arg(pack.path())
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout).trim(),
+        "explicit inherited"
+    );
+}